@@ -0,0 +1,482 @@
+use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Read, Seek},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use flate2::read::{DeflateDecoder, GzDecoder};
+use tracing::debug;
+use zip::CompressionMethod;
+
+use crate::reader::{self, IoStrategy};
+use crate::Throttle;
+
+/// Separates the archive path from the member path, e.g. `backup.zip!/docs/a.pdf`.
+pub const SEPARATOR: &str = "!/";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ArchiveKind {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+impl ArchiveKind {
+    fn detect(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_string_lossy().to_ascii_lowercase();
+        if name.ends_with(".zip") {
+            Some(Self::Zip)
+        } else if name.ends_with(".tar") {
+            Some(Self::Tar)
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(Self::TarGz)
+        } else {
+            None
+        }
+    }
+}
+
+pub fn is_archive(path: &Path) -> bool {
+    ArchiveKind::detect(path).is_some()
+}
+
+/// Virtual path of `member` within `archive`.
+pub fn member_path(archive: &Path, member: impl AsRef<OsStr>) -> PathBuf {
+    let mut path = archive.as_os_str().to_owned();
+    path.push(SEPARATOR);
+    path.push(member);
+    PathBuf::from(path)
+}
+
+/// Split a virtual path into the archive holding it and the member name.
+/// Returns `None` for ordinary files.
+/// Neither the archive's path nor the member name need be UTF-8.
+pub fn split(path: &Path) -> Option<(PathBuf, OsString)> {
+    let bytes = path.as_os_str().as_encoded_bytes();
+    let separator = SEPARATOR.as_bytes();
+    bytes
+        .windows(separator.len())
+        .enumerate()
+        .find_map(|(idx, window)| {
+            if window != separator {
+                return None;
+            }
+            let archive = prefix(path, idx)?;
+            let member = suffix(path, idx + separator.len())?;
+            (archive.is_file() && is_archive(archive))
+                .then(|| (archive.to_owned(), member.to_owned()))
+        })
+}
+
+#[cfg(unix)]
+fn prefix(path: &Path, len: usize) -> Option<&Path> {
+    use std::os::unix::ffi::OsStrExt;
+    Some(Path::new(OsStr::from_bytes(
+        &path.as_os_str().as_bytes()[..len],
+    )))
+}
+
+#[cfg(not(unix))]
+fn prefix(path: &Path, len: usize) -> Option<&Path> {
+    path.to_str()?.get(..len).map(Path::new)
+}
+
+#[cfg(unix)]
+fn suffix(path: &Path, start: usize) -> Option<&OsStr> {
+    use std::os::unix::ffi::OsStrExt;
+    Some(OsStr::from_bytes(&path.as_os_str().as_bytes()[start..]))
+}
+
+#[cfg(not(unix))]
+fn suffix(path: &Path, start: usize) -> Option<&OsStr> {
+    path.to_str()?.get(start..).map(OsStr::new)
+}
+
+pub fn is_member(path: &Path) -> bool {
+    split(path).is_some()
+}
+
+/// List the regular files within `archive`, as (size, virtual path) pairs,
+/// reading it at `throttle`'s rate. A tar is indexed for its members to be
+/// opened later.
+pub fn members(archive: &Path, throttle: &Throttle) -> io::Result<Vec<(u64, PathBuf)>> {
+    let kind = ArchiveKind::detect(archive)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not an archive"))?;
+    debug!(archive = debug(archive), kind = debug(kind), "members");
+    match kind {
+        ArchiveKind::Zip => {
+            let file = BufReader::new(throttle.reader(File::open(archive)?));
+            let mut zip = zip::ZipArchive::new(file)?;
+            let mut members = Vec::new();
+            for idx in 0..zip.len() {
+                let file = zip.by_index(idx)?;
+                if file.is_file() {
+                    members.push((file.size(), member_path(archive, file.name())));
+                }
+            }
+            Ok(members)
+        }
+        ArchiveKind::Tar | ArchiveKind::TarGz => {
+            let tar = indexed_tar(archive, kind, throttle)?;
+            Ok(tar
+                .names
+                .iter()
+                .map(|name| (tar.members[name].1, member_path(archive, name)))
+                .collect())
+        }
+    }
+}
+
+/// Open a file for reading, looking inside archives for virtual paths.
+pub fn open(path: &Path) -> io::Result<Box<dyn BufRead>> {
+    open_with(path, IoStrategy::Plain)
+}
+
+/// As [`open`], reading files (but not archive members) with `strategy`.
+pub fn open_with(path: &Path, strategy: IoStrategy) -> io::Result<Box<dyn BufRead>> {
+    match split(path) {
+        None => reader::open(path, strategy),
+        Some((archive, member)) => open_member(&archive, &member),
+    }
+}
+
+// Members are streamed from their offset within the archive: stored zip members and tar
+// members are read in place, deflated zip members through a decoder, and a gzipped tar is
+// decompressed once into a spill file which later opens share.
+fn open_member(archive: &Path, member: &OsStr) -> io::Result<Box<dyn BufRead>> {
+    let kind = ArchiveKind::detect(archive)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not an archive"))?;
+    match kind {
+        ArchiveKind::Zip => {
+            // Zip names are always decoded to strings.
+            let name = member.to_str().ok_or_else(|| not_found(member))?;
+            let file = File::open(archive)?;
+            let mut zip = zip::ZipArchive::new(file.try_clone()?)?;
+            let entry = zip.by_name(name)?;
+            let section = Section::new(Arc::new(file), entry.data_start(), entry.compressed_size());
+            Ok(match entry.compression() {
+                CompressionMethod::Stored => Box::new(BufReader::new(section)),
+                CompressionMethod::Deflated => {
+                    Box::new(BufReader::new(DeflateDecoder::new(section)))
+                }
+                method => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        format!("{method} compression"),
+                    ))
+                }
+            })
+        }
+        ArchiveKind::Tar | ArchiveKind::TarGz => {
+            let tar = indexed_tar(archive, kind, &Throttle::default())?;
+            let &(offset, size) = tar.members.get(member).ok_or_else(|| not_found(member))?;
+            Ok(Box::new(BufReader::new(Section::new(
+                tar.file.clone(),
+                offset,
+                size,
+            ))))
+        }
+    }
+}
+
+fn not_found(member: &OsStr) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("{member:?}"))
+}
+
+/// An uncompressed tar, with the offset and size of each regular file within it.
+struct IndexedTar {
+    modified: SystemTime,
+    len: u64,
+    file: Arc<File>,
+    members: HashMap<OsString, (u64, u64)>,
+    /// Member names in archive order.
+    names: Vec<OsString>,
+}
+
+lazy_static::lazy_static! {
+    static ref INDEXED_TARS: Mutex<HashMap<PathBuf, Arc<IndexedTar>>> = Mutex::new(HashMap::new());
+}
+
+/// Index `archive`, decompressing it into a spill file if need be, reading
+/// it at `throttle`'s rate. Each archive is indexed once, unless it has
+/// since changed on disk.
+fn indexed_tar(
+    archive: &Path,
+    kind: ArchiveKind,
+    throttle: &Throttle,
+) -> io::Result<Arc<IndexedTar>> {
+    let mut indexed = INDEXED_TARS.lock().unwrap_or_else(|e| e.into_inner());
+    let metadata = fs::metadata(archive)?;
+    let modified = metadata.modified()?;
+    if let Some(tar) = indexed.get(archive) {
+        if tar.modified == modified && tar.len == metadata.len() {
+            return Ok(tar.clone());
+        }
+    }
+    let file = match kind {
+        ArchiveKind::TarGz => {
+            debug!(archive = debug(archive), "spill");
+            let mut spill = spill_file()?;
+            let file = BufReader::new(throttle.reader(File::open(archive)?));
+            io::copy(&mut GzDecoder::new(file), &mut spill)?;
+            spill.rewind()?;
+            spill
+        }
+        _ => File::open(archive)?,
+    };
+    let mut members = HashMap::new();
+    let mut names = Vec::new();
+    let mut tar = tar::Archive::new(throttle.reader(&file));
+    for entry in tar.entries_with_seek()? {
+        let entry = entry?;
+        if entry.header().entry_type().is_file() {
+            let name = entry.path()?.into_owned().into_os_string();
+            let position = (entry.raw_file_position(), entry.size());
+            if members.insert(name.clone(), position).is_none() {
+                names.push(name);
+            }
+        }
+    }
+    let tar = Arc::new(IndexedTar {
+        modified,
+        len: metadata.len(),
+        file: Arc::new(file),
+        members,
+        names,
+    });
+    indexed.insert(archive.to_owned(), tar.clone());
+    Ok(tar)
+}
+
+/// An anonymous temporary file, removed once closed.
+#[cfg(unix)]
+fn spill_file() -> io::Result<File> {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    static SPILLED: AtomicUsize = AtomicUsize::new(0);
+
+    let path = std::env::temp_dir().join(format!(
+        "fdupes-{}-{}.tar",
+        std::process::id(),
+        SPILLED.fetch_add(1, Ordering::Relaxed)
+    ));
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    fs::remove_file(&path)?;
+    Ok(file)
+}
+
+#[cfg(windows)]
+fn spill_file() -> io::Result<File> {
+    use std::os::windows::fs::OpenOptionsExt;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    const FILE_FLAG_DELETE_ON_CLOSE: u32 = 0x0400_0000;
+    static SPILLED: AtomicUsize = AtomicUsize::new(0);
+
+    let path = std::env::temp_dir().join(format!(
+        "fdupes-{}-{}.tar",
+        std::process::id(),
+        SPILLED.fetch_add(1, Ordering::Relaxed)
+    ));
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .custom_flags(FILE_FLAG_DELETE_ON_CLOSE)
+        .open(&path)
+}
+
+/// Reads `len` bytes of a shared file from `offset`, independently of its other readers.
+struct Section {
+    file: Arc<File>,
+    offset: u64,
+    end: u64,
+}
+
+impl Section {
+    fn new(file: Arc<File>, offset: u64, len: u64) -> Self {
+        Self {
+            file,
+            offset,
+            end: offset + len,
+        }
+    }
+}
+
+impl Read for Section {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = usize::try_from(self.end - self.offset).unwrap_or(usize::MAX);
+        let len = buf.len().min(remaining);
+        if len == 0 {
+            return Ok(0);
+        }
+        let read = read_at(&self.file, &mut buf[..len], self.offset)?;
+        self.offset += read as u64;
+        Ok(read)
+    }
+}
+
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(file, buf, offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::Write;
+
+    lazy_static::lazy_static! {
+    static ref TEST_DATA1: &'static Path = Path::new("test_data/file1.txt");
+    static ref ZIP_FILENAME: &'static Path = Path::new("test_data/archive_scratch.zip");
+    static ref TAR_FILENAME: &'static Path = Path::new("test_data/archive_scratch.tar");
+    static ref TAR_GZ_FILENAME: &'static Path = Path::new("test_data/archive_scratch.tar.gz");
+    }
+
+    #[test]
+    fn split_ordinary() {
+        assert_eq!(split(&TEST_DATA1), None);
+        assert_eq!(split(Path::new("test_data/missing.zip!/a.txt")), None);
+    }
+
+    #[test]
+    fn zip_members() {
+        let content = fs::read(*TEST_DATA1).unwrap();
+        let mut zip = zip::ZipWriter::new(fs::File::create(*ZIP_FILENAME).unwrap());
+        zip.start_file("docs/a.txt", zip::write::FileOptions::default())
+            .unwrap();
+        zip.write_all(&content).unwrap();
+        zip.finish().unwrap();
+
+        let listed = members(&ZIP_FILENAME, &Throttle::default()).unwrap();
+        let member = member_path(&ZIP_FILENAME, "docs/a.txt");
+        assert_eq!(listed, vec![(content.len() as u64, member.clone())]);
+        assert_eq!(
+            split(&member),
+            Some((ZIP_FILENAME.to_path_buf(), "docs/a.txt".into()))
+        );
+        let mut read = Vec::new();
+        open(&member).unwrap().read_to_end(&mut read).unwrap();
+        assert_eq!(read, content);
+
+        fs::remove_file(*ZIP_FILENAME).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn split_non_utf8_archive() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let archive = Path::new(OsStr::from_bytes(b"test_data/archive_scratch_\xe9.zip"));
+        let mut zip = zip::ZipWriter::new(fs::File::create(archive).unwrap());
+        zip.finish().unwrap();
+
+        let member = member_path(archive, "docs/a.txt");
+        assert_eq!(
+            split(&member),
+            Some((archive.to_path_buf(), "docs/a.txt".into()))
+        );
+
+        fs::remove_file(archive).unwrap();
+    }
+
+    #[test]
+    fn tar_members() {
+        let content = fs::read(*TEST_DATA1).unwrap();
+        let mut tar = tar::Builder::new(fs::File::create(*TAR_FILENAME).unwrap());
+        tar.append_path_with_name(*TEST_DATA1, "docs/a.txt")
+            .unwrap();
+        tar.finish().unwrap();
+
+        let member = member_path(&TAR_FILENAME, "docs/a.txt");
+        assert_eq!(
+            members(&TAR_FILENAME, &Throttle::default()).unwrap(),
+            vec![(content.len() as u64, member.clone())]
+        );
+        let mut read = Vec::new();
+        open(&member).unwrap().read_to_end(&mut read).unwrap();
+        assert_eq!(read, content);
+
+        fs::remove_file(*TAR_FILENAME).unwrap();
+    }
+
+    #[test]
+    fn tar_gz_members() {
+        let contents = [fs::read(*TEST_DATA1).unwrap(), b"second".to_vec()];
+        let gz = flate2::write::GzEncoder::new(
+            fs::File::create(*TAR_GZ_FILENAME).unwrap(),
+            flate2::Compression::default(),
+        );
+        let mut tar = tar::Builder::new(gz);
+        for (name, content) in ["a.txt", "b.txt"].iter().zip(&contents) {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            tar.append_data(&mut header, name, content.as_slice())
+                .unwrap();
+        }
+        tar.into_inner().unwrap().finish().unwrap();
+
+        // Interleaved readers of one spilled archive each see their own member.
+        let mut readers = ["b.txt", "a.txt", "b.txt"]
+            .map(|name| open(&member_path(&TAR_GZ_FILENAME, name)).unwrap());
+        let mut read = [Vec::new(), Vec::new(), Vec::new()];
+        for (reader, read) in readers.iter_mut().zip(read.iter_mut()) {
+            reader.read_to_end(read).unwrap();
+        }
+        assert_eq!(
+            read,
+            [&contents[1], &contents[0], &contents[1]].map(Vec::clone)
+        );
+
+        fs::remove_file(*TAR_GZ_FILENAME).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn tar_non_utf8_members() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let archive = Path::new("test_data/archive_scratch_names.tar");
+        let names: [&[u8]; 2] = [b"docs/caf\xe9.txt", b"docs/caf\xe8.txt"];
+        let names = names.map(OsStr::from_bytes);
+        let mut tar = tar::Builder::new(fs::File::create(archive).unwrap());
+        for (name, content) in names.iter().zip(["first", "second"]) {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            tar.append_data(&mut header, name, content.as_bytes())
+                .unwrap();
+        }
+        tar.finish().unwrap();
+
+        let listed = members(archive, &Throttle::default()).unwrap();
+        let expected = names.map(|name| member_path(archive, name));
+        assert_eq!(
+            listed.iter().map(|(_, path)| path).collect::<Vec<_>>(),
+            expected.iter().collect::<Vec<_>>()
+        );
+        let mut read = String::new();
+        open(&expected[1])
+            .unwrap()
+            .read_to_string(&mut read)
+            .unwrap();
+        assert_eq!(read, "second");
+
+        fs::remove_file(archive).unwrap();
+    }
+}
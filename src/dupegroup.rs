@@ -0,0 +1,249 @@
+use std::path::PathBuf;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Layout version of [`DupeGroup`], bumped on incompatible changes.
+pub const DUPE_GROUP_VERSION: u32 = 1;
+
+/// A group of files found to hold the same content.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DupeGroup {
+    pub version: u32,
+    /// Position of this group's bucket amongst the `total` compared by the
+    /// scan; groups split from one bucket share it.
+    pub id: usize,
+    pub total: usize,
+    /// Size of the compared content of each file.
+    pub size: u64,
+    /// Name of the comparator which matched the files.
+    pub comparator: String,
+    /// Digest of the shared content, when one was computed: `sha256:` and its
+    /// SHA-256 in hex, or `fingerprint:` and the perceptual hash of files
+    /// grouped as similar.
+    pub digest: Option<String>,
+    pub files: Vec<DupeFile>,
+}
+
+/// A single file within a [`DupeGroup`], with its metadata where available.
+///
+/// Paths serialize as strings, or as arrays of raw bytes when they are not
+/// valid UTF-8.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DupeFile {
+    #[serde(with = "lossless")]
+    pub path: PathBuf,
+    /// Scan root the file was found under.
+    #[serde(with = "lossless::option")]
+    pub root: Option<PathBuf>,
+    /// Size on disk, which may differ from the group size for normalising comparators.
+    pub size: Option<u64>,
+    pub modified: Option<DateTime<Utc>>,
+    pub device: Option<u64>,
+    pub inode: Option<u64>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+impl DupeGroup {
+    pub fn new(
+        id: usize,
+        total: usize,
+        size: u64,
+        comparator: &str,
+        digest: Option<String>,
+        filenames: Vec<PathBuf>,
+        roots: &[PathBuf],
+    ) -> Self {
+        Self {
+            version: DUPE_GROUP_VERSION,
+            id,
+            total,
+            size,
+            comparator: comparator.to_owned(),
+            digest,
+            files: filenames
+                .into_iter()
+                .map(|path| DupeFile::new(path, roots))
+                .collect(),
+        }
+    }
+
+    pub fn filenames(&self) -> impl Iterator<Item = &PathBuf> {
+        self.files.iter().map(|file| &file.path)
+    }
+}
+
+impl DupeFile {
+    /// Describe the file at `path`; metadata is left empty when it cannot be
+    /// read, e.g. for archive members.
+    pub fn new(path: PathBuf, roots: &[PathBuf]) -> Self {
+        let root = roots.iter().find(|root| path.starts_with(root)).cloned();
+        let mut file = Self {
+            path,
+            root,
+            size: None,
+            modified: None,
+            device: None,
+            inode: None,
+            uid: None,
+            gid: None,
+        };
+        if let Ok(metadata) = file.path.metadata() {
+            file.size = Some(metadata.len());
+            file.modified = metadata.modified().ok().map(DateTime::<Utc>::from);
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::MetadataExt;
+                file.device = Some(metadata.dev());
+                file.inode = Some(metadata.ino());
+                file.uid = Some(metadata.uid());
+                file.gid = Some(metadata.gid());
+            }
+        }
+        file
+    }
+}
+
+/// Serde representation of paths which survives names that aren't UTF-8.
+pub(crate) mod lossless {
+    use std::path::{Path, PathBuf};
+
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(untagged)]
+    enum Repr {
+        Text(String),
+        Bytes(Vec<u8>),
+    }
+
+    impl From<&Path> for Repr {
+        fn from(path: &Path) -> Self {
+            match path.to_str() {
+                Some(text) => Self::Text(text.to_owned()),
+                None => Self::Bytes(bytes(path)),
+            }
+        }
+    }
+
+    impl From<Repr> for PathBuf {
+        fn from(repr: Repr) -> Self {
+            match repr {
+                Repr::Text(text) => PathBuf::from(text),
+                Repr::Bytes(bytes) => from_bytes(bytes),
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    fn bytes(path: &Path) -> Vec<u8> {
+        use std::os::unix::ffi::OsStrExt;
+        path.as_os_str().as_bytes().to_vec()
+    }
+
+    #[cfg(not(unix))]
+    fn bytes(path: &Path) -> Vec<u8> {
+        path.to_string_lossy().into_owned().into_bytes()
+    }
+
+    #[cfg(unix)]
+    fn from_bytes(bytes: Vec<u8>) -> PathBuf {
+        use std::os::unix::ffi::OsStringExt;
+        PathBuf::from(std::ffi::OsString::from_vec(bytes))
+    }
+
+    #[cfg(not(unix))]
+    fn from_bytes(bytes: Vec<u8>) -> PathBuf {
+        PathBuf::from(String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    pub fn serialize<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+        Repr::from(path).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+        Repr::deserialize(deserializer).map(PathBuf::from)
+    }
+
+    pub mod option {
+        use std::path::PathBuf;
+
+        use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+        use super::Repr;
+
+        pub fn serialize<S: Serializer>(
+            path: &Option<PathBuf>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            path.as_deref().map(Repr::from).serialize(serializer)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<PathBuf>, D::Error> {
+            Option::<Repr>::deserialize(deserializer).map(|repr| repr.map(PathBuf::from))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metadata() {
+        let group = DupeGroup::new(
+            0,
+            1,
+            1211,
+            "exact",
+            None,
+            vec![
+                PathBuf::from("test_data/file1.txt"),
+                PathBuf::from("test_data/missing.txt"),
+            ],
+            &[PathBuf::from("test_data")],
+        );
+        let file = &group.files[0];
+        assert_eq!(file.root, Some(PathBuf::from("test_data")));
+        assert_eq!(file.size, Some(1211));
+        assert!(file.modified.is_some());
+        let missing = &group.files[1];
+        assert_eq!(missing.size, None);
+        assert_eq!(missing.modified, None);
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let group = DupeGroup::new(
+            2,
+            5,
+            1211,
+            "exact",
+            Some("sha256:12ab".to_owned()),
+            vec![PathBuf::from("test_data/file1.txt")],
+            &[],
+        );
+        let json = serde_json::to_string(&group).unwrap();
+        assert_eq!(serde_json::from_str::<DupeGroup>(&json).unwrap(), group);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn json_non_utf8_path() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = PathBuf::from(OsStr::from_bytes(b"share/caf\xe9.txt"));
+        let group = DupeGroup::new(0, 1, 3, "exact", None, vec![path.clone()], &[]);
+        let json = serde_json::to_value(&group).unwrap();
+        assert_eq!(
+            json["files"][0]["path"],
+            serde_json::json!(b"share/caf\xe9.txt".to_vec())
+        );
+        let group = serde_json::from_value::<DupeGroup>(json).unwrap();
+        assert_eq!(group.files[0].path, path);
+    }
+}
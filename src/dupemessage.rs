@@ -1,3 +0,0 @@
-use std::path::PathBuf;
-
-pub type DupeMessage = (u64, usize, usize, Vec<PathBuf>);
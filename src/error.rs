@@ -0,0 +1,57 @@
+use std::io;
+use std::path::PathBuf;
+use std::sync::mpsc::SendError;
+
+use thiserror::Error;
+
+/// Errors raised while scanning for, or resolving, duplicates.
+#[derive(Debug, Error)]
+pub enum FdupesError {
+    #[error("{}: {source}", path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("no files in group")]
+    EmptyGroup,
+    #[error("comparator {0:?} registered more than once")]
+    DuplicateComparator(String),
+    #[error("unknown comparator {0:?}")]
+    UnknownComparator(String),
+    #[error("receiver disconnected")]
+    Disconnected,
+    #[error("cancelled")]
+    Cancelled,
+    #[error("cannot watch for changes: {0}")]
+    Watch(#[from] notify::Error),
+    #[error(transparent)]
+    Output(#[from] io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, FdupesError>;
+
+impl FdupesError {
+    pub fn io(path: impl Into<PathBuf>, source: io::Error) -> Self {
+        Self::Io {
+            path: path.into(),
+            source,
+        }
+    }
+
+    /// Exit code for the process to report this error with.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Self::Io { .. } | Self::EmptyGroup | Self::Watch(_) => 1,
+            Self::DuplicateComparator(_) | Self::UnknownComparator(_) => 2,
+            Self::Disconnected | Self::Output(_) => 3,
+            Self::Cancelled => 130,
+        }
+    }
+}
+
+impl<T> From<SendError<T>> for FdupesError {
+    fn from(_: SendError<T>) -> Self {
+        Self::Disconnected
+    }
+}
@@ -0,0 +1,298 @@
+//! Purge journals: a JSON line appended for each file deleted or trashed,
+//! before it happens, for `undo` to reverse. A purge that then fails is
+//! followed by a second entry saying so.
+
+use std::{
+    collections::HashSet,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::dupegroup::lossless;
+use crate::error::{FdupesError, Result};
+use crate::DupeGroup;
+
+/// Layout version of [`JournalEntry`], bumped on incompatible changes.
+pub const JOURNAL_VERSION: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    /// Removed for good.
+    Delete,
+    /// Moved to the trash, from where it can be restored.
+    Trash,
+}
+
+/// A file purged from a group of duplicates.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub version: u32,
+    pub action: Action,
+    /// Absolute path of the purged file.
+    #[serde(with = "lossless")]
+    pub path: PathBuf,
+    /// A copy kept from the same group, unless all were purged.
+    #[serde(with = "lossless::option")]
+    pub kept: Option<PathBuf>,
+    pub size: u64,
+    /// Digest of the group's content, when one was computed.
+    pub digest: Option<String>,
+    pub timestamp: DateTime<Utc>,
+    /// Why the purge recorded by the previous entry for this path failed;
+    /// `None` for the entry written ahead of it.
+    pub failed: Option<String>,
+}
+
+impl JournalEntry {
+    pub fn new(action: Action, path: PathBuf, kept: Option<PathBuf>, group: &DupeGroup) -> Self {
+        Self {
+            version: JOURNAL_VERSION,
+            action,
+            path,
+            kept,
+            size: group.size,
+            digest: group.digest.clone(),
+            timestamp: Utc::now(),
+            failed: None,
+        }
+    }
+
+    /// The entry recording that the purge written ahead as `self` failed.
+    pub fn failure(&self, reason: impl Into<String>) -> Self {
+        Self {
+            timestamp: Utc::now(),
+            failed: Some(reason.into()),
+            ..self.clone()
+        }
+    }
+}
+
+/// A journal open for appending.
+pub struct Journal {
+    file: File,
+}
+
+impl Journal {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("journal {path:?}: {e}")))?;
+        Ok(Self { file })
+    }
+
+    /// Append `entry`, on disk before returning.
+    pub fn record(&mut self, entry: &JournalEntry) -> io::Result<()> {
+        let mut line = serde_json::to_vec(entry).map_err(io::Error::from)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.file.sync_data()
+    }
+}
+
+/// Every entry in the journal at `path`, oldest first.
+pub fn read(path: &Path) -> Result<Vec<JournalEntry>> {
+    let file = File::open(path).map_err(|e| FdupesError::io(path, e))?;
+    let mut entries = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line.map_err(|e| FdupesError::io(path, e))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: JournalEntry =
+            serde_json::from_str(&line).map_err(|e| FdupesError::io(path, io::Error::from(e)))?;
+        if entry.version != JOURNAL_VERSION {
+            let message = format!("journal version {} is not supported", entry.version);
+            return Err(FdupesError::io(
+                path,
+                io::Error::new(io::ErrorKind::InvalidData, message),
+            ));
+        }
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// What became of an entry when undone.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Undone {
+    /// Put back from the trash.
+    Restored,
+    /// Left alone, as a file is already at its path.
+    Present,
+    /// Deleted for good, so only listed.
+    Permanent,
+    Failed(String),
+}
+
+/// Restore the trashed files of `entries`, newest first, leaving any whose
+/// path has since been taken. Deleted files cannot be restored; they are
+/// reported as [`Undone::Permanent`], with `kept` the copy to restore from.
+/// Purges recorded as failed are skipped, as there is nothing to undo.
+pub fn undo(entries: &[JournalEntry]) -> Vec<(&JournalEntry, Undone)> {
+    let mut trash = None;
+    let mut failed = HashSet::new();
+    entries
+        .iter()
+        .rev()
+        .filter_map(|entry| {
+            if entry.failed.is_some() {
+                failed.insert(&entry.path);
+                return None;
+            }
+            if failed.remove(&entry.path) {
+                return None;
+            }
+            let undone = match entry.action {
+                _ if entry.path.symlink_metadata().is_ok() => Undone::Present,
+                Action::Delete => Undone::Permanent,
+                Action::Trash => restore(&entry.path, &mut trash),
+            };
+            Some((entry, undone))
+        })
+        .collect()
+}
+
+/// Restore the file last trashed from `path`. The trash is listed once, on
+/// first use.
+#[cfg(any(
+    windows,
+    all(
+        unix,
+        not(target_os = "macos"),
+        not(target_os = "ios"),
+        not(target_os = "android")
+    )
+))]
+fn restore(path: &Path, trash: &mut Option<Vec<trash::TrashItem>>) -> Undone {
+    use trash::os_limited;
+
+    let items = match trash {
+        Some(items) => items,
+        None => match os_limited::list() {
+            Ok(items) => trash.insert(items),
+            Err(e) => return Undone::Failed(format!("cannot list the trash: {e}")),
+        },
+    };
+    let latest = items
+        .iter()
+        .enumerate()
+        .filter(|(_, item)| item.original_path() == path)
+        .max_by_key(|(_, item)| item.time_deleted)
+        .map(|(position, _)| position);
+    match latest {
+        Some(position) => match os_limited::restore_all([items.remove(position)]) {
+            Ok(()) => Undone::Restored,
+            Err(e) => Undone::Failed(e.to_string()),
+        },
+        None => Undone::Failed("no longer in the trash".to_owned()),
+    }
+}
+
+#[cfg(not(any(
+    windows,
+    all(
+        unix,
+        not(target_os = "macos"),
+        not(target_os = "ios"),
+        not(target_os = "android")
+    )
+)))]
+fn restore(_path: &Path, _trash: &mut Option<Vec<()>>) -> Undone {
+    Undone::Failed("restoring from the trash is not supported on this platform".to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read, undo, Action, Journal, JournalEntry, Undone};
+    use crate::DupeGroup;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    lazy_static::lazy_static! {
+    static ref JOURNAL: &'static Path = Path::new("test_data/journal_scratch.jsonl");
+    static ref TEST_DATA1: &'static Path = Path::new("test_data/file1.txt");
+    static ref TRASHED: &'static Path = Path::new("test_data/journal_trashed_scratch.txt");
+    }
+
+    #[test]
+    fn record_and_undo() {
+        let group = DupeGroup::new(
+            0,
+            1,
+            16,
+            "exact",
+            Some("sha256:12ab".to_owned()),
+            Vec::new(),
+            &[],
+        );
+        let deleted = JournalEntry::new(
+            Action::Delete,
+            PathBuf::from("/nonexistent/copy"),
+            Some(TEST_DATA1.to_path_buf()),
+            &group,
+        );
+        // Already back in place, so left alone rather than sought in the trash.
+        let trashed = JournalEntry::new(Action::Trash, TEST_DATA1.to_path_buf(), None, &group);
+        // Never purged, so nothing to undo.
+        let attempted = JournalEntry::new(
+            Action::Trash,
+            PathBuf::from("/nonexistent/busy"),
+            None,
+            &group,
+        );
+        let failed = attempted.failure("permission denied");
+
+        let _ = fs::remove_file(*JOURNAL);
+        for entry in [&deleted, &trashed, &attempted, &failed] {
+            Journal::open(&JOURNAL).unwrap().record(entry).unwrap();
+        }
+        let entries = read(&JOURNAL).unwrap();
+        fs::remove_file(*JOURNAL).unwrap();
+
+        assert_eq!(
+            entries,
+            vec![
+                deleted.clone(),
+                trashed.clone(),
+                attempted.clone(),
+                failed.clone()
+            ]
+        );
+        assert_eq!(
+            undo(&entries),
+            vec![(&trashed, Undone::Present), (&deleted, Undone::Permanent)]
+        );
+    }
+
+    #[cfg(any(
+        windows,
+        all(
+            unix,
+            not(target_os = "macos"),
+            not(target_os = "ios"),
+            not(target_os = "android")
+        )
+    ))]
+    #[test]
+    fn restore_trashed() {
+        fs::write(*TRASHED, "restore me").unwrap();
+        let path = fs::canonicalize(*TRASHED).unwrap();
+        let group = DupeGroup::new(0, 1, 10, "exact", None, Vec::new(), &[]);
+        let entry = JournalEntry::new(Action::Trash, path.clone(), None, &group);
+        trash::delete(&path).unwrap();
+
+        let undone = undo(std::slice::from_ref(&entry));
+        let content = fs::read_to_string(&path);
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(undone, vec![(&entry, Undone::Restored)]);
+        assert_eq!(content.unwrap(), "restore me");
+    }
+}
@@ -1,7 +1,18 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
 
-mod dupemessage;
+mod archive;
+mod cancel;
+mod dupegroup;
+mod error;
+pub mod journal;
+pub mod manifest;
+mod progress;
+mod reader;
+mod scan;
 mod scanner;
+mod similarmessage;
+mod throttle;
 
 pub mod receiver;
 
@@ -9,7 +20,7 @@ pub mod receiver;
 #[clap(author, version, about, long_about = None)]
 pub struct Config {
     /// Path(s) to search for files within.
-    pub roots: Vec<String>,
+    pub roots: Vec<PathBuf>,
     /// Only find duplicates immediately within supplied directories.
     #[clap(short, long)]
     pub non_recursive: bool,
@@ -25,11 +36,153 @@ pub struct Config {
     /// purge files into trash, rather than permanently.
     #[clap(short = 't', long)]
     pub trash: bool,
+    /// append each file purged to FILE, for `undo` to reverse.
+    #[clap(long, value_name = "FILE")]
+    pub journal: Option<PathBuf>,
+    /// compare the decompressed content of gzip, xz, zstd and bzip2 files.
+    #[clap(short = 'z', long)]
+    pub decompress: bool,
+    /// treat members of zip and tar archives as files (reported, never deleted).
+    #[clap(short = 'A', long)]
+    pub archives: bool,
+    /// also group visually similar images (JPEG, PNG, WebP, GIF).
+    #[clap(long)]
+    pub images: bool,
+    /// maximum perceptual hash distance (in bits) between similar images.
+    #[clap(long, default_value_t = 8)]
+    pub image_distance: u32,
+    /// also group JPEG, MP3 and FLAC files whose payloads match, ignoring metadata tags.
+    #[clap(long)]
+    pub ignore_metadata: bool,
+    /// most files to hold open at once when comparing a group of candidates.
+    #[clap(long, default_value_t = 256)]
+    pub max_open: usize,
+    /// evenly spaced blocks compared, after the first and last, before reading whole files.
+    #[clap(long, default_value_t = 4)]
+    pub samples: usize,
+    /// how files are read while comparing: auto (fadvise for large files, plain otherwise), mmap, fadvise (large reads kept out of the page cache) or plain.
+    #[clap(long, value_enum, default_value_t)]
+    pub io: IoStrategy,
+    /// order in which each group's files are first read: walk, inode, or extent (physical offset, via FIEMAP).
+    #[clap(long, value_enum, default_value_t)]
+    pub read_order: ReadOrder,
+    /// most bytes to read per second while comparing, e.g. 50MiB/s.
+    #[clap(long, value_name = "RATE", value_parser = throttle::parse_rate)]
+    pub max_read_rate: Option<u64>,
+    /// scan at idle I/O priority and lowest CPU priority (Linux).
+    #[clap(long)]
+    pub low_priority: bool,
+    /// save scan progress to FILE as it goes, for `--resume` to continue from.
+    #[clap(long, value_name = "FILE", conflicts_with_all = ["dirs", "low_memory", "similar"])]
+    pub checkpoint: Option<PathBuf>,
+    /// continue from the checkpoint, unless it is missing or of a scan with other roots or options.
+    #[clap(long, requires = "checkpoint")]
+    pub resume: bool,
+    /// walk the roots twice, holding only files whose size recurs, to bound memory use.
+    #[clap(long, conflicts_with_all = ["dirs", "similar"])]
+    pub low_memory: bool,
+    /// report files at least PERCENT similar (by fuzzy hash) instead of exact duplicates; nothing is purged.
+    #[clap(long, value_name = "PERCENT")]
+    pub similar: Option<u32>,
+    /// report directories whose whole contents are duplicated, rather than each file within them.
+    #[clap(long)]
+    pub dirs: bool,
+    /// require file names to match too when comparing directories.
+    #[clap(long, requires = "dirs")]
+    pub dir_names: bool,
+    /// list files in the first root with no identical copy in the other roots (or anywhere, given one root).
+    #[clap(long, conflicts_with_all = ["dirs", "similar", "checkpoint", "low_memory"])]
+    pub unique: bool,
+    /// keep every file's metadata and group in FILE between runs, comparing only files changed since the last.
+    #[clap(long, value_name = "FILE", conflicts_with_all = ["dirs", "similar", "checkpoint", "low_memory", "unique"])]
+    pub index: Option<PathBuf>,
+    /// print duplicate groups as JSON lines instead of prompting.
+    #[clap(long)]
+    pub json: bool,
     /// use classic display mode (non-tui).
     #[clap(long, default_value_t = true)]
     pub classic_mode: bool,
+    #[clap(subcommand)]
+    pub command: Option<Command>,
 }
 
-pub use crate::dupemessage::DupeMessage;
-pub use crate::scanner::DupeScanner;
-pub use crate::scanner::group_comparator::{GroupComparator, ExactGroupComparator, JsonGroupComparator};
+#[derive(Debug, Clone, Subcommand)]
+pub enum Command {
+    /// record each file's metadata and digests as JSON lines, without grouping duplicates.
+    Scan {
+        /// file to write the manifest to.
+        #[clap(long, value_name = "FILE")]
+        manifest: PathBuf,
+        /// Path(s) to search for files within.
+        roots: Vec<PathBuf>,
+    },
+    /// scan, then keep watching the roots, printing each group as JSON lines whenever a new or changed file joins it.
+    Watch {
+        /// Path(s) to search for files within.
+        roots: Vec<PathBuf>,
+    },
+    /// print the duplicate groups across manifests written by `scan` as JSON lines; nothing is purged.
+    Merge {
+        /// manifests to group files from.
+        #[clap(required = true)]
+        manifests: Vec<PathBuf>,
+    },
+    /// restore the files a journal records as trashed, newest first, and list those deleted for good.
+    Undo {
+        /// journal written by `--journal`.
+        journal: PathBuf,
+    },
+}
+
+impl Default for Config {
+    /// The same defaults as the command line, for building a `Config` without clap.
+    fn default() -> Self {
+        Self {
+            roots: Vec::new(),
+            non_recursive: false,
+            min_size: 0,
+            show_sizes: false,
+            prompt: false,
+            trash: false,
+            journal: None,
+            decompress: false,
+            archives: false,
+            images: false,
+            image_distance: 8,
+            ignore_metadata: false,
+            max_open: 256,
+            samples: 4,
+            io: IoStrategy::Auto,
+            read_order: ReadOrder::Walk,
+            max_read_rate: None,
+            low_priority: false,
+            checkpoint: None,
+            resume: false,
+            low_memory: false,
+            similar: None,
+            dirs: false,
+            dir_names: false,
+            unique: false,
+            index: None,
+            json: false,
+            classic_mode: true,
+            command: None,
+        }
+    }
+}
+
+pub use crate::cancel::CancellationToken;
+pub use crate::dupegroup::{DupeFile, DupeGroup, DUPE_GROUP_VERSION};
+pub use crate::error::{FdupesError, Result};
+pub use crate::journal::{JournalEntry, JOURNAL_VERSION};
+pub use crate::manifest::{ManifestEntry, MANIFEST_VERSION};
+pub use crate::progress::{ProgressEvent, ProgressStage};
+pub use crate::reader::IoStrategy;
+pub use crate::scan::{Scan, ScanBuilder, ScanIter};
+pub use crate::scanner::{DupeScanner, GroupChanges, ReadOrder, SimilarityScanner};
+pub use crate::similarmessage::SimilarMessage;
+pub use crate::throttle::Throttle;
+pub use crate::scanner::group_comparator::{
+    DecompressGroupComparator, ExactGroupComparator, GroupComparator, ImageGroupComparator,
+    JsonGroupComparator, MediaGroupComparator,
+};
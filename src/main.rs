@@ -2,17 +2,28 @@ extern crate chrono;
 
 use clap::Parser;
 use fdupes::receiver::DupeGroupReceiver;
-use fdupes::{ExactGroupComparator, JsonGroupComparator};
+use fdupes::{
+    DecompressGroupComparator, ExactGroupComparator, GroupComparator, ImageGroupComparator,
+    JsonGroupComparator, MediaGroupComparator,
+};
 use tracing::Level;
 use tracing_subscriber::fmt::format::FmtSpan;
 
+use std::fs::File;
+use std::io::{self, BufWriter, IsTerminal};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{self, Receiver, Sender};
 use std::sync::Arc;
 use std::{env, thread};
 
 use fdupes::receiver::*;
-use fdupes::{Config, DupeMessage, DupeScanner};
+use fdupes::{
+    journal, manifest, CancellationToken, Command, Config, DupeGroup, DupeScanner, FdupesError,
+    GroupChanges, ProgressEvent, SimilarMessage, SimilarityScanner,
+};
 
 fn setup_logger() {
     // install global collector configured based on RUST_LOG env var.
@@ -28,33 +39,303 @@ fn setup_logger() {
         .init();
 }
 
-fn setup(rx: Receiver<DupeMessage>, config: &Config) -> Box<dyn DupeGroupReceiver> {
-    if config.classic_mode {
+fn setup(
+    rx: Receiver<DupeGroup>,
+    config: &Config,
+    cancel: &CancellationToken,
+    progress: Option<Receiver<ProgressEvent>>,
+) -> Box<dyn DupeGroupReceiver> {
+    if config.json {
+        Box::new(JsonReceiver::new(rx))
+    } else if config.unique {
+        Box::new(UniqueReceiver::new(rx))
+    } else if config.classic_mode {
         setup_logger();
-        Box::new(BasicReceiver::new(rx, config.to_owned()))
+        let receiver = BasicReceiver::new(rx, config.to_owned()).with_cancellation(cancel.clone());
+        match progress {
+            Some(progress) => Box::new(receiver.with_progress(progress)),
+            None => Box::new(receiver),
+        }
     } else {
         panic!()
     }
 }
 
-fn main() {
+/// Cancel on the first SIGINT/SIGTERM so the current group is finished
+/// cleanly; a second signal exits immediately. Returns whether a signal arrived.
+fn handle_interrupts(cancel: CancellationToken) -> Arc<AtomicBool> {
+    let interrupted = Arc::new(AtomicBool::new(false));
+    let flag = interrupted.clone();
+    let installed = ctrlc::set_handler(move || {
+        if flag.swap(true, Ordering::SeqCst) {
+            std::process::exit(130);
+        }
+        eprintln!("\nInterrupted: stopping after the current step (again to exit immediately)");
+        cancel.cancel();
+    });
+    if let Err(e) = installed {
+        eprintln!("fdupes: cannot handle interrupts: {e}");
+    }
+    interrupted
+}
+
+/// Wait for the receiver and scanner to finish, reporting the first failure.
+/// The scanner is expected to be disconnected or cancelled when the receiver
+/// stops early.
+fn finish(
+    receiver: thread::JoinHandle<fdupes::Result<()>>,
+    scanner: thread::JoinHandle<fdupes::Result<()>>,
+    interrupted: &AtomicBool,
+) -> ExitCode {
+    let receiver = receiver.join();
+    let scanner = scanner.join();
+    let result = match (receiver, scanner) {
+        (Err(_), _) | (_, Err(_)) => return ExitCode::from(101),
+        (Ok(Err(e)), _) => Err(e),
+        (Ok(Ok(())), Ok(Err(FdupesError::Disconnected | FdupesError::Cancelled))) => Ok(()),
+        (Ok(Ok(())), Ok(result)) => result,
+    };
+    match result {
+        Ok(()) if interrupted.load(Ordering::SeqCst) => ExitCode::from(130),
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("fdupes: {e}");
+            ExitCode::from(e.exit_code())
+        }
+    }
+}
+
+fn find_similar(config: Config, threshold: u32) -> ExitCode {
+    setup_logger();
+    let (tx, rx): (Sender<SimilarMessage>, Receiver<SimilarMessage>) = mpsc::channel();
+
+    let cancel = CancellationToken::new();
+    let interrupted = handle_interrupts(cancel.clone());
+    let mut receiver = SimilarityReceiver::new(rx);
+    let scanner = SimilarityScanner::new(tx, Arc::new(config), threshold).with_cancellation(cancel);
+
+    let receiver = thread::spawn(move || receiver.run());
+    let scanner = thread::spawn(move || scanner.find_clusters());
+
+    finish(receiver, scanner, &interrupted)
+}
+
+/// Comparators selected by `config`.
+fn comparators(config: &Config) -> Vec<Box<dyn GroupComparator>> {
+    let exact: Box<dyn GroupComparator> = if config.decompress {
+        Box::new(DecompressGroupComparator::new().with_io(config.io))
+    } else {
+        Box::new(ExactGroupComparator::new().with_io(config.io))
+    };
+    let mut comparators = vec![exact, Box::new(JsonGroupComparator::new())];
+    if config.images {
+        comparators.push(Box::new(ImageGroupComparator::with_max_distance(
+            config.image_distance,
+        )));
+    }
+    if config.ignore_metadata {
+        comparators.push(Box::new(MediaGroupComparator::new()));
+    }
+    comparators
+}
+
+fn scan_manifest(mut config: Config, roots: Vec<PathBuf>, manifest: &Path) -> ExitCode {
+    setup_logger();
+    config.roots = roots;
+    let out = match File::create(manifest) {
+        Ok(out) => BufWriter::new(out),
+        Err(e) => {
+            let e = FdupesError::io(manifest, e);
+            eprintln!("fdupes: {e}");
+            return ExitCode::from(e.exit_code());
+        }
+    };
+    let cancel = CancellationToken::new();
+    // Groups are never sent when recording a manifest.
+    let (groups, _) = mpsc::channel();
+    let comparators = comparators(&config);
+    let scanner = match DupeScanner::new(groups, Arc::new(config), comparators) {
+        Ok(scanner) => scanner.with_cancellation(cancel.clone()),
+        Err(e) => {
+            eprintln!("fdupes: {e}");
+            return ExitCode::from(e.exit_code());
+        }
+    };
+    let interrupted = handle_interrupts(cancel);
+
+    let (tx, rx) = mpsc::channel();
+    let receiver = thread::spawn(move || manifest::write(rx, out));
+    let scanner = thread::spawn(move || scanner.find_manifest(tx));
+
+    finish(receiver, scanner, &interrupted)
+}
+
+fn watch(mut config: Config, roots: Vec<PathBuf>) -> ExitCode {
+    config.roots = roots;
+    let (tx, rx) = mpsc::channel();
+    let cancel = CancellationToken::new();
+    let comparators = comparators(&config);
+    let scanner = match DupeScanner::new(tx, Arc::new(config), comparators) {
+        Ok(scanner) => scanner.with_cancellation(cancel.clone()),
+        Err(e) => {
+            eprintln!("fdupes: {e}");
+            return ExitCode::from(e.exit_code());
+        }
+    };
+    let interrupted = handle_interrupts(cancel);
+
+    let mut receiver = JsonReceiver::new(rx);
+    let receiver = thread::spawn(move || receiver.run());
+    let scanner = thread::spawn(move || scanner.watch());
+
+    finish(receiver, scanner, &interrupted)
+}
+
+fn merge_manifests(manifests: &[PathBuf]) -> ExitCode {
+    let mut entries = Vec::new();
+    for path in manifests {
+        match manifest::read(path) {
+            Ok(read) => entries.extend(read),
+            Err(e) => {
+                eprintln!("fdupes: {e}");
+                return ExitCode::from(e.exit_code());
+            }
+        }
+    }
+    let (tx, rx) = mpsc::channel();
+    for group in manifest::merge(entries) {
+        // The receiver below outlives this loop, so the send cannot fail.
+        let _ = tx.send(group);
+    }
+    drop(tx);
+    match JsonReceiver::new(rx).run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("fdupes: {e}");
+            ExitCode::from(e.exit_code())
+        }
+    }
+}
+
+fn undo(path: &Path) -> ExitCode {
+    let entries = match journal::read(path) {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("fdupes: {e}");
+            return ExitCode::from(e.exit_code());
+        }
+    };
+    let (mut restored, mut permanent, mut failed) = (0, 0, 0);
+    for (entry, undone) in journal::undo(&entries) {
+        match undone {
+            journal::Undone::Restored => {
+                println!("Restored {:?}", entry.path);
+                restored += 1;
+            }
+            journal::Undone::Present => {
+                println!("Skipped {:?}: a file is already there", entry.path);
+            }
+            journal::Undone::Permanent => {
+                match &entry.kept {
+                    Some(kept) => {
+                        println!("Deleted for good {:?} (copy kept at {kept:?})", entry.path)
+                    }
+                    None => println!("Deleted for good {:?} (no copy kept)", entry.path),
+                }
+                permanent += 1;
+            }
+            journal::Undone::Failed(e) => {
+                eprintln!("Failed to restore {:?}: {e}", entry.path);
+                failed += 1;
+            }
+        }
+    }
+    eprintln!("{restored} restored, {permanent} deleted for good, {failed} failed");
+    if failed > 0 {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+/// Print how the groups found differ from those of the last scan, on stderr
+/// so as not to mix with the groups themselves.
+fn report_changes(changes: &GroupChanges, json: bool) {
+    if json {
+        match serde_json::to_string(changes) {
+            Ok(line) => eprintln!("{line}"),
+            Err(e) => eprintln!("fdupes: {e}"),
+        }
+        return;
+    }
+    for (groups, what) in [(&changes.new, "new"), (&changes.resolved, "resolved")] {
+        eprintln!("{} {what} groups since the last scan", groups.len());
+        for group in groups {
+            for filename in group.filenames() {
+                eprintln!("  {filename:?}");
+            }
+            eprintln!();
+        }
+    }
+}
+
+fn main() -> ExitCode {
     let config = Config::parse();
+    match config.command.clone() {
+        Some(Command::Scan { manifest, roots }) => return scan_manifest(config, roots, &manifest),
+        Some(Command::Merge { manifests }) => return merge_manifests(&manifests),
+        Some(Command::Watch { roots }) => return watch(config, roots),
+        Some(Command::Undo { journal }) => return undo(&journal),
+        None => {}
+    }
+    if let Some(threshold) = config.similar {
+        return find_similar(config, threshold);
+    }
 
-    let (tx, rx): (Sender<DupeMessage>, Receiver<DupeMessage>) = mpsc::channel();
+    let (tx, rx): (Sender<DupeGroup>, Receiver<DupeGroup>) = mpsc::channel();
 
-    let mut receiver = setup(rx, &config);
-    let scanner = DupeScanner::new(
-        tx,
-        Arc::new(config.clone()),
-        vec![
-            Box::new(ExactGroupComparator::new()),
-            Box::new(JsonGroupComparator::new()),
-        ],
-    );
+    let cancel = CancellationToken::new();
+    // Progress is drawn on stderr, so only when someone is watching it.
+    let (progress_tx, progress_rx) = if !config.json && io::stderr().is_terminal() {
+        let (tx, rx) = mpsc::channel();
+        (Some(tx), Some(rx))
+    } else {
+        (None, None)
+    };
+    let mut receiver = setup(rx, &config, &cancel, progress_rx);
+    let comparators = comparators(&config);
+    let scanner = match DupeScanner::new(tx, Arc::new(config.clone()), comparators) {
+        Ok(scanner) => {
+            let scanner = scanner.with_cancellation(cancel.clone());
+            match progress_tx {
+                Some(progress) => scanner.with_progress(progress),
+                None => scanner,
+            }
+        }
+        Err(e) => {
+            eprintln!("fdupes: {e}");
+            return ExitCode::from(e.exit_code());
+        }
+    };
 
+    let interrupted = handle_interrupts(cancel);
+
+    let (changes_tx, changes_rx) = mpsc::channel();
+    let index = config.index.clone();
     let receiver = thread::spawn(move || receiver.run());
-    let scanner = thread::spawn(move || scanner.find_groups());
+    let scanner = thread::spawn(move || match index {
+        Some(index) => {
+            let changes = scanner.find_groups_incremental(&index)?;
+            // Reported once the groups themselves have been.
+            let _ = changes_tx.send(changes);
+            Ok(())
+        }
+        None => scanner.find_groups(),
+    });
 
-    receiver.join().unwrap().unwrap();
-    scanner.join().unwrap();
+    let code = finish(receiver, scanner, &interrupted);
+    if let Ok(changes) = changes_rx.try_recv() {
+        report_changes(&changes, config.json);
+    }
+    code
 }
@@ -0,0 +1,219 @@
+//! Scan manifests: each file's metadata and digests as JSON lines, written
+//! by `scan --manifest` on one machine and grouped with others' by `merge`.
+
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::mpsc::Receiver,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{FdupesError, Result};
+use crate::{DupeFile, DupeGroup};
+
+/// Layout version of [`ManifestEntry`], bumped on incompatible changes.
+pub const MANIFEST_VERSION: u32 = 2;
+
+/// Partial CRC, full CRC, SHA-256 and fingerprint of a file's content.
+pub type Digests = (Option<u16>, Option<u16>, Option<String>, Option<u64>);
+
+/// A file as seen by one comparator: its metadata, and the digests
+/// compared when scanning.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub version: u32,
+    #[serde(flatten)]
+    pub file: DupeFile,
+    /// Name of the comparator the digests were taken with.
+    pub comparator: String,
+    /// Size of the compared content, which may differ from the file size
+    /// for normalising comparators.
+    pub content_size: u64,
+    /// CRC-16/USB of the first block.
+    pub partialcrc: Option<u16>,
+    /// CRC-16/X25 of the whole content.
+    pub fullcrc: Option<u16>,
+    /// SHA-256 of the whole content, in hex: what entries are merged on.
+    pub sha256: Option<String>,
+    /// Perceptual fingerprint, for comparators grouping similar content;
+    /// the CRCs are then left out.
+    pub fingerprint: Option<u64>,
+}
+
+impl ManifestEntry {
+    pub fn new(
+        path: PathBuf,
+        roots: &[PathBuf],
+        comparator: &str,
+        content_size: u64,
+        (partialcrc, fullcrc, sha256, fingerprint): Digests,
+    ) -> Self {
+        Self {
+            version: MANIFEST_VERSION,
+            file: DupeFile::new(path, roots),
+            comparator: comparator.to_owned(),
+            content_size,
+            partialcrc,
+            fullcrc,
+            sha256,
+            fingerprint,
+        }
+    }
+}
+
+/// Write each entry received as a line of JSON.
+pub fn write(rx: Receiver<ManifestEntry>, mut out: impl Write) -> Result<()> {
+    while let Ok(entry) = rx.recv() {
+        serde_json::to_writer(&mut out, &entry).map_err(io::Error::from)?;
+        writeln!(out)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Every entry in the manifest at `path`.
+pub fn read(path: &Path) -> Result<Vec<ManifestEntry>> {
+    let file = File::open(path).map_err(|e| FdupesError::io(path, e))?;
+    let mut entries = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line.map_err(|e| FdupesError::io(path, e))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: ManifestEntry =
+            serde_json::from_str(&line).map_err(|e| FdupesError::io(path, io::Error::from(e)))?;
+        if entry.version != MANIFEST_VERSION {
+            let message = format!("manifest version {} is not supported", entry.version);
+            return Err(FdupesError::io(
+                path,
+                io::Error::new(io::ErrorKind::InvalidData, message),
+            ));
+        }
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// Group entries, from any number of manifests, whose comparator, content
+/// size and SHA-256 or fingerprint match. Files are not read again, so
+/// matches rest on the digests alone, and fingerprints must match exactly.
+/// Entries with neither digest are left out. Groups come largest first, as
+/// from a scan.
+pub fn merge(entries: impl IntoIterator<Item = ManifestEntry>) -> Vec<DupeGroup> {
+    type Key = (u64, String, Option<String>, Option<u64>);
+    let mut buckets: BTreeMap<Key, Vec<DupeFile>> = BTreeMap::new();
+    for entry in entries {
+        if entry.sha256.is_none() && entry.fingerprint.is_none() {
+            continue;
+        }
+        let key = (
+            entry.content_size,
+            entry.comparator,
+            entry.sha256,
+            entry.fingerprint,
+        );
+        buckets.entry(key).or_default().push(entry.file);
+    }
+    let buckets = buckets
+        .into_iter()
+        .rev()
+        .filter(|(_, files)| files.len() > 1)
+        .collect::<Vec<_>>();
+    let total = buckets.len();
+    buckets
+        .into_iter()
+        .enumerate()
+        .map(|(id, ((size, comparator, sha256, fingerprint), files))| {
+            let digest = match (fingerprint, sha256) {
+                (Some(fingerprint), _) => Some(format!("fingerprint:{fingerprint:016x}")),
+                (None, Some(sha256)) => Some(format!("sha256:{sha256}")),
+                (None, None) => None,
+            };
+            let mut group = DupeGroup::new(id, total, size, &comparator, digest, Vec::new(), &[]);
+            group.files = files;
+            group
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{merge, read, write, ManifestEntry};
+    use crate::{Config, DupeScanner, ExactGroupComparator};
+    use std::fs;
+    use std::path::Path;
+    use std::sync::{mpsc, Arc};
+
+    lazy_static::lazy_static! {
+    static ref SCRATCH: &'static Path = Path::new("test_data/manifest_scratch");
+    static ref MANIFEST: &'static Path = Path::new("test_data/manifest_scratch.jsonl");
+    static ref TEST_DATA1: &'static Path = Path::new("test_data/file1.txt");
+    static ref TEST_DATA2: &'static Path = Path::new("test_data/file2.txt");
+    }
+
+    #[test]
+    fn scan_and_merge() {
+        let roots = ["x", "y"].map(|root| SCRATCH.join(root));
+        for root in &roots {
+            fs::create_dir_all(root).unwrap();
+        }
+        fs::copy(*TEST_DATA1, roots[0].join("a.txt")).unwrap();
+        fs::copy(*TEST_DATA2, roots[0].join("b.txt")).unwrap();
+        fs::copy(*TEST_DATA1, roots[1].join("c.txt")).unwrap();
+
+        // One manifest per root, as if scanned on separate machines.
+        let mut entries = Vec::new();
+        for root in &roots {
+            let config = Config {
+                roots: vec![root.clone()],
+                ..Config::default()
+            };
+            let (groups, _) = mpsc::channel();
+            let scanner = DupeScanner::new(
+                groups,
+                Arc::new(config),
+                vec![Box::new(ExactGroupComparator::new())],
+            )
+            .unwrap();
+            let (tx, rx) = mpsc::channel();
+            scanner.find_manifest(tx).unwrap();
+            write(rx, fs::File::create(*MANIFEST).unwrap()).unwrap();
+            entries.extend(read(&MANIFEST).unwrap());
+        }
+        fs::remove_file(*MANIFEST).unwrap();
+        fs::remove_dir_all(*SCRATCH).unwrap();
+
+        assert_eq!(entries.len(), 3);
+        let groups = merge(entries);
+        assert_eq!(groups.len(), 1);
+        let filenames = groups[0].filenames().cloned().collect::<Vec<_>>();
+        assert_eq!(
+            filenames,
+            vec![roots[0].join("a.txt"), roots[1].join("c.txt")]
+        );
+        assert_eq!(groups[0].files[1].root.as_ref(), Some(&roots[1]));
+    }
+
+    #[test]
+    fn merge_on_sha256() {
+        // Same size and CRCs, as a CRC collision would leave them.
+        let entry = |path: &Path, sha256: &str| {
+            ManifestEntry::new(
+                path.to_path_buf(),
+                &[],
+                "exact",
+                1211,
+                (Some(1), Some(2), Some(sha256.to_owned()), None),
+            )
+        };
+        let colliding = vec![entry(&TEST_DATA1, "aa"), entry(&TEST_DATA2, "bb")];
+        assert!(merge(colliding).is_empty());
+
+        let groups = merge(vec![entry(&TEST_DATA1, "aa"), entry(&TEST_DATA2, "aa")]);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].digest.as_deref(), Some("sha256:aa"));
+    }
+}
@@ -0,0 +1,220 @@
+use std::io::{self, BufRead, Read};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Least time between two intermediate progress events.
+const INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressStage {
+    /// Low-memory mode's first walk, counting sizes.
+    Counting,
+    /// Walking the roots for candidate files.
+    Walking,
+    /// Sizing candidate files, with each comparator, into buckets.
+    Bucketing,
+    /// Comparing the files within each bucket.
+    Comparing,
+    Done,
+}
+
+/// Snapshot of a scan's progress. Counts are running totals, so only the
+/// latest event matters.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProgressEvent {
+    pub stage: ProgressStage,
+    pub files_discovered: u64,
+    pub bytes_hashed: u64,
+    pub buckets_done: usize,
+    pub buckets_total: usize,
+    pub elapsed: Duration,
+    /// Estimated time left comparing, once a bucket has been done.
+    pub eta: Option<Duration>,
+}
+
+/// Collects progress and reports it, at most every [`INTERVAL`] apart from
+/// stage changes. Does nothing unless built with a subscriber.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Progress {
+    inner: Option<Arc<Inner>>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    tx: Sender<ProgressEvent>,
+    start: Instant,
+    files: AtomicU64,
+    bytes: AtomicU64,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    stage: ProgressStage,
+    buckets_done: usize,
+    buckets_total: usize,
+    compare_start: Option<Instant>,
+    last_sent: Option<Instant>,
+}
+
+impl Progress {
+    pub fn new(tx: Sender<ProgressEvent>) -> Self {
+        Self {
+            inner: Some(Arc::new(Inner {
+                tx,
+                start: Instant::now(),
+                files: AtomicU64::new(0),
+                bytes: AtomicU64::new(0),
+                state: Mutex::new(State {
+                    stage: ProgressStage::Walking,
+                    buckets_done: 0,
+                    buckets_total: 0,
+                    compare_start: None,
+                    last_sent: None,
+                }),
+            })),
+        }
+    }
+
+    pub fn stage(&self, stage: ProgressStage) {
+        self.update(true, |state| state.stage = stage);
+    }
+
+    pub fn discovered(&self, files: u64) {
+        if let Some(inner) = &self.inner {
+            inner.files.fetch_add(files, Ordering::Relaxed);
+            self.update(false, |_| ());
+        }
+    }
+
+    pub fn hashed(&self, bytes: u64) {
+        if let Some(inner) = &self.inner {
+            inner.bytes.fetch_add(bytes, Ordering::Relaxed);
+            self.update(false, |_| ());
+        }
+    }
+
+    /// Start comparing `buckets_total` buckets.
+    pub fn comparing(&self, buckets_total: usize) {
+        self.update(true, |state| {
+            state.stage = ProgressStage::Comparing;
+            state.buckets_total = buckets_total;
+            state.compare_start = Some(Instant::now());
+        });
+    }
+
+    pub fn bucket_done(&self) {
+        self.update(false, |state| state.buckets_done += 1);
+    }
+
+    /// Count the bytes consumed from `reader` as hashed.
+    pub fn wrap(&self, reader: Box<dyn BufRead>) -> Box<dyn BufRead> {
+        if self.inner.is_some() {
+            Box::new(Counted {
+                reader,
+                progress: self.clone(),
+            })
+        } else {
+            reader
+        }
+    }
+
+    fn update(&self, force: bool, change: impl FnOnce(&mut State)) {
+        let Some(inner) = &self.inner else {
+            return;
+        };
+        let mut state = match inner.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        change(&mut state);
+        let now = Instant::now();
+        if !force && state.last_sent.is_some_and(|last| now - last < INTERVAL) {
+            return;
+        }
+        state.last_sent = Some(now);
+        let eta = state
+            .compare_start
+            .filter(|_| state.buckets_done > 0)
+            .map(|start| {
+                let remaining = state.buckets_total.saturating_sub(state.buckets_done);
+                (now - start).mul_f64(remaining as f64 / state.buckets_done as f64)
+            });
+        // Nobody listening is not an error: progress is informational.
+        let _ = inner.tx.send(ProgressEvent {
+            stage: state.stage,
+            files_discovered: inner.files.load(Ordering::Relaxed),
+            bytes_hashed: inner.bytes.load(Ordering::Relaxed),
+            buckets_done: state.buckets_done,
+            buckets_total: state.buckets_total,
+            elapsed: now - inner.start,
+            eta,
+        });
+    }
+}
+
+struct Counted {
+    reader: Box<dyn BufRead>,
+    progress: Progress,
+}
+
+impl Read for Counted {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.reader.read(buf)?;
+        self.progress.hashed(read as u64);
+        Ok(read)
+    }
+}
+
+impl BufRead for Counted {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.reader.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.reader.consume(amt);
+        self.progress.hashed(amt as u64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::sync::mpsc;
+
+    #[test]
+    fn events() {
+        let (tx, rx) = mpsc::channel();
+        let progress = Progress::new(tx);
+        progress.discovered(3);
+        progress.stage(ProgressStage::Bucketing);
+        progress.comparing(2);
+        let mut reader = progress.wrap(Box::new(Cursor::new(vec![0_u8; 10])));
+        io::copy(&mut reader, &mut io::sink()).unwrap();
+        progress.bucket_done();
+        progress.bucket_done();
+        progress.stage(ProgressStage::Done);
+
+        let events = rx.try_iter().collect::<Vec<_>>();
+        let last = events.last().unwrap();
+        assert_eq!(last.stage, ProgressStage::Done);
+        assert_eq!(last.files_discovered, 3);
+        assert_eq!(last.bytes_hashed, 10);
+        assert_eq!((last.buckets_done, last.buckets_total), (2, 2));
+        assert_eq!(last.eta, Some(Duration::ZERO));
+        assert!(events
+            .iter()
+            .any(|event| event.stage == ProgressStage::Bucketing));
+    }
+
+    #[test]
+    fn disabled() {
+        let progress = Progress::default();
+        progress.discovered(1);
+        let mut reader = progress.wrap(Box::new(Cursor::new(vec![0_u8; 4])));
+        assert_eq!(io::copy(&mut reader, &mut io::sink()).unwrap(), 4);
+    }
+}
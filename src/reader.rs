@@ -0,0 +1,195 @@
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, Cursor, Read},
+    path::Path,
+};
+
+use memmap2::Mmap;
+use tracing::debug;
+
+/// Size of each read while comparing files in lockstep, and of the buffer
+/// for uncached reads.
+pub(crate) const BLOCK_SIZE: usize = 64 * 1024;
+
+/// How much of a file is read uncached before its pages are dropped.
+const DROP_INTERVAL: u64 = 8 * 1024 * 1024;
+
+/// Smallest file read uncached by [`IoStrategy::Auto`]. Smaller files are
+/// cheaper to read plainly than to buffer and evict.
+const UNCACHED_MIN_SIZE: u64 = 64 * 1024 * 1024;
+
+/// How files are read while hashing and comparing.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum IoStrategy {
+    /// Uncached reads of large files where supported, plain reads otherwise.
+    #[default]
+    Auto,
+    /// Map files into memory.
+    Mmap,
+    /// Sequential reads, dropped from the page cache as they go.
+    Fadvise,
+    /// Buffered reads.
+    Plain,
+}
+
+/// Open a local file for reading with `strategy`.
+pub(crate) fn open(path: &Path, strategy: IoStrategy) -> io::Result<Box<dyn BufRead>> {
+    let file = File::open(path)?;
+    match strategy {
+        IoStrategy::Auto
+            if cfg!(target_os = "linux") && file.metadata()?.len() >= UNCACHED_MIN_SIZE =>
+        {
+            uncached(file)
+        }
+        IoStrategy::Auto | IoStrategy::Plain => Ok(Box::new(BufReader::new(file))),
+        IoStrategy::Mmap => mapped(file),
+        IoStrategy::Fadvise => uncached(file),
+    }
+}
+
+fn mapped(file: File) -> io::Result<Box<dyn BufRead>> {
+    // Empty files cannot be mapped everywhere, and have nothing to read.
+    if file.metadata()?.len() == 0 {
+        return Ok(Box::new(io::empty()));
+    }
+    // SAFETY: the map is only read. A file truncated while mapped faults on
+    // access, the same hazard as every mmap based reader.
+    let map = unsafe { Mmap::map(&file)? };
+    #[cfg(unix)]
+    if let Err(e) = map.advise(memmap2::Advice::Sequential) {
+        debug!(error = debug(e), "madvise");
+    }
+    Ok(Box::new(Cursor::new(map)))
+}
+
+fn uncached(file: File) -> io::Result<Box<dyn BufRead>> {
+    advise(&file, Advice::Sequential, 0, 0);
+    Ok(Box::new(BufReader::with_capacity(
+        BLOCK_SIZE,
+        Uncached {
+            file,
+            read: 0,
+            dropped: 0,
+        },
+    )))
+}
+
+/// File whose cached pages are dropped as it is read, every
+/// [`DROP_INTERVAL`], and once it is closed, so a scan does not evict
+/// everyone else's working set.
+struct Uncached {
+    file: File,
+    read: u64,
+    dropped: u64,
+}
+
+impl Read for Uncached {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.file.read(buf)?;
+        self.read += read as u64;
+        if self.read - self.dropped >= DROP_INTERVAL {
+            advise(
+                &self.file,
+                Advice::DontNeed,
+                self.dropped,
+                self.read - self.dropped,
+            );
+            self.dropped = self.read;
+        }
+        Ok(read)
+    }
+}
+
+impl Drop for Uncached {
+    fn drop(&mut self) {
+        advise(&self.file, Advice::DontNeed, 0, 0);
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Advice {
+    Sequential,
+    DontNeed,
+}
+
+/// Advise the kernel about `len` bytes of `file` from `offset`; a `len` of
+/// zero reaches the end of the file.
+#[cfg(target_os = "linux")]
+fn advise(file: &File, advice: Advice, offset: u64, len: u64) {
+    use std::os::unix::io::AsRawFd;
+
+    let advice = match advice {
+        Advice::Sequential => libc::POSIX_FADV_SEQUENTIAL,
+        Advice::DontNeed => libc::POSIX_FADV_DONTNEED,
+    };
+    // SAFETY: the descriptor stays open for the duration of the call.
+    let result = unsafe {
+        libc::posix_fadvise(
+            file.as_raw_fd(),
+            offset as libc::off_t,
+            len as libc::off_t,
+            advice,
+        )
+    };
+    if result != 0 {
+        debug!(
+            error = debug(io::Error::from_raw_os_error(result)),
+            "fadvise"
+        );
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn advise(_file: &File, _advice: Advice, _offset: u64, _len: u64) {}
+
+#[cfg(test)]
+mod tests {
+    use super::{open, IoStrategy, DROP_INTERVAL};
+    use clap::ValueEnum;
+    use std::fs;
+    use std::io::Read;
+    use std::path::Path;
+
+    lazy_static::lazy_static! {
+    static ref TEST_DATA1: &'static Path = Path::new("test_data/file1.txt");
+    static ref EMPTY_FILENAME: &'static Path = Path::new("test_data/reader_empty_scratch.txt");
+    static ref LARGE_FILENAME: &'static Path = Path::new("test_data/reader_large_scratch.bin");
+    }
+
+    #[test]
+    fn strategies_agree() {
+        let expected = fs::read(*TEST_DATA1).unwrap();
+        fs::write(*EMPTY_FILENAME, b"").unwrap();
+        for strategy in IoStrategy::value_variants() {
+            let mut content = Vec::new();
+            open(&TEST_DATA1, *strategy)
+                .unwrap()
+                .read_to_end(&mut content)
+                .unwrap();
+            assert_eq!(content, expected, "{strategy:?}");
+
+            let mut content = Vec::new();
+            open(&EMPTY_FILENAME, *strategy)
+                .unwrap()
+                .read_to_end(&mut content)
+                .unwrap();
+            assert!(content.is_empty(), "{strategy:?}");
+        }
+        fs::remove_file(*EMPTY_FILENAME).unwrap();
+    }
+
+    #[test]
+    fn uncached_reads_past_drops() {
+        let expected = (0..DROP_INTERVAL * 2 + 10)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        fs::write(*LARGE_FILENAME, &expected).unwrap();
+        let mut content = Vec::new();
+        open(&LARGE_FILENAME, IoStrategy::Fadvise)
+            .unwrap()
+            .read_to_end(&mut content)
+            .unwrap();
+        fs::remove_file(*LARGE_FILENAME).unwrap();
+        assert!(content == expected);
+    }
+}
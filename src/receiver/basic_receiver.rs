@@ -1,21 +1,63 @@
-use crate::{Config, DupeMessage};
+use crate::journal::{Action, Journal, JournalEntry};
+use crate::{archive, CancellationToken, Config, DupeGroup, ProgressEvent};
 use num_format::{Locale, ToFormattedString};
-use std::path::PathBuf;
-use std::{io, io::Write, sync::mpsc::Receiver};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::Duration;
+use std::{fmt, fs, io, io::Write};
 use tracing::debug;
 
-use super::{mark_group, DupeGroup, DupeGroupReceiver, Mark};
+use super::progress_bar::ProgressBar;
+use super::{mark_group, DupeGroupReceiver, Mark, MarkedGroup};
+
+/// How often the progress bar is redrawn while waiting for a group.
+const REFRESH: Duration = Duration::from_millis(100);
 
 pub struct BasicReceiver {
-    rx: Receiver<DupeMessage>,
+    rx: Receiver<DupeGroup>,
     config: Config,
+    cancel: CancellationToken,
+    summary: Summary,
+    progress: Option<Receiver<ProgressEvent>>,
+    bar: ProgressBar,
+}
+
+/// Actions taken so far, reported when the receiver finishes.
+#[derive(Debug, Default)]
+struct Summary {
+    groups: usize,
+    deleted: usize,
+    trashed: usize,
+    freed: u64,
+    failed: usize,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} groups reviewed: {} deleted, {} trashed ({} bytes), {} failed",
+            self.groups,
+            self.deleted,
+            self.trashed,
+            self.freed.to_formatted_string(&Locale::en_GB),
+            self.failed
+        )
+    }
 }
 
 impl DupeGroupReceiver for BasicReceiver {
-    fn run(&mut self) -> Result<(), std::io::Error> {
-        while let Ok((size, total, id, filenames)) = self.rx.recv() {
-            debug!("{} {:?}", size, filenames);
-            Self::handle_group(size, id, total, filenames, &self.config);
+    fn run(&mut self) -> crate::Result<()> {
+        while !self.cancel.is_cancelled() {
+            let group = match self.next_group() {
+                Some(group) => group,
+                None => break,
+            };
+            debug!("{:?}", group);
+            self.handle_group(&group)?;
+        }
+        io::stdout().flush()?;
+        if self.summary.groups > 0 || self.cancel.is_cancelled() {
+            eprintln!("{}", self.summary);
         }
         /*
         loop {
@@ -33,11 +75,55 @@ impl DupeGroupReceiver for BasicReceiver {
 }
 
 impl BasicReceiver {
-    pub fn new(rx: Receiver<DupeMessage>, config: Config) -> Self {
-        Self { rx, config }
+    pub fn new(rx: Receiver<DupeGroup>, config: Config) -> Self {
+        Self {
+            rx,
+            config,
+            cancel: CancellationToken::new(),
+            summary: Summary::default(),
+            progress: None,
+            bar: ProgressBar::default(),
+        }
     }
 
-    fn process_input(buffer: &str, files: &mut DupeGroup) -> bool {
+    /// Show scan progress on stderr whenever waiting for the next group.
+    pub fn with_progress(mut self, progress: Receiver<ProgressEvent>) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    fn next_group(&mut self) -> Option<DupeGroup> {
+        let progress = match &self.progress {
+            Some(progress) => progress,
+            None => return self.rx.recv().ok(),
+        };
+        loop {
+            match self.rx.recv_timeout(REFRESH) {
+                Ok(group) => {
+                    self.bar.clear();
+                    return Some(group);
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    if let Some(event) = progress.try_iter().last() {
+                        self.bar.draw(&event);
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    self.bar.clear();
+                    return None;
+                }
+            }
+        }
+    }
+
+    /// Stop prompting, leaving remaining groups untouched, once `cancel` is
+    /// cancelled. `quit` cancels it too.
+    pub fn with_cancellation(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    fn process_input(buffer: &str, files: &mut MarkedGroup, cancel: &CancellationToken) -> bool {
         let mut done = false;
         for choice in buffer.split(|c: char| c.is_whitespace() || c == ',') {
             let choice = choice.trim();
@@ -45,7 +131,10 @@ impl BasicReceiver {
                 continue;
             }
             match choice {
-                "quit" => std::process::exit(0),
+                "quit" => {
+                    cancel.cancel();
+                    return true;
+                }
                 "none" => {
                     mark_group(files, Mark::Purge);
                     done = true;
@@ -67,20 +156,31 @@ impl BasicReceiver {
         done
     }
 
-    fn handle_group(size: u64, id: usize, total: usize, filenames: Vec<PathBuf>, config: &Config) {
+    fn handle_group(&mut self, group: &DupeGroup) -> io::Result<()> {
+        let config = &self.config;
+        // A group grown during the scan comes again; copies purged since are gone.
+        let filenames = group
+            .filenames()
+            .filter(|f| archive::is_member(f) || f.symlink_metadata().is_ok())
+            .collect::<Vec<_>>();
+        let size = group.size;
         if filenames.len() > 1 {
             for (id, filename) in filenames.iter().enumerate() {
-                println!("[{}] {:?} (W)", id + 1, filename);
+                if archive::is_member(filename) {
+                    println!("[{}] {:?} (archive member)", id + 1, filename);
+                } else {
+                    println!("[{}] {:?} (W)", id + 1, filename);
+                }
             }
             let files = loop {
                 let mut files = filenames
                     .iter()
-                    .map(|f| (f, Mark::Purge))
-                    .collect::<DupeGroup>();
+                    .map(|f| (*f, Mark::Purge))
+                    .collect::<MarkedGroup>();
                 print!(
                     "({}/{}) Preserve files [1 - {}, all, none, quit]",
-                    id,
-                    total,
+                    group.id,
+                    group.total,
                     filenames.len()
                 );
                 if config.show_sizes {
@@ -91,37 +191,81 @@ impl BasicReceiver {
                     }
                 }
                 print!(": ");
-                io::stdout().flush().unwrap();
-                let mut done = false;
-
+                io::stdout().flush()?;
                 let mut buffer = String::new();
-                if io::stdin().read_line(&mut buffer).is_ok() {
-                    done = Self::process_input(&buffer, &mut files);
+                if io::stdin().read_line(&mut buffer)? == 0 {
+                    // No more answers to come: treat end of input as quit.
+                    println!();
+                    self.cancel.cancel();
                 }
+                let done = Self::process_input(&buffer, &mut files, &self.cancel);
 
+                if self.cancel.is_cancelled() {
+                    return Ok(());
+                }
                 if done {
                     break files;
                 }
             };
 
+            self.summary.groups += 1;
+            // Nothing is purged unless it can be recorded.
+            let mut journal = config.journal.as_deref().map(Journal::open).transpose()?;
+            let kept = files
+                .iter()
+                .find(|(_, mark)| *mark == Mark::Keep)
+                .map(|(filename, _)| fs::canonicalize(filename).unwrap_or(filename.to_path_buf()));
             for (filename, mark) in files {
                 if Mark::Purge == mark {
-                    if config.trash {
-                        if let Err(e) = trash::delete(filename) {
-                            eprintln!("Failed to put {filename:?} in trash: {e}");
+                    if archive::is_member(filename) {
+                        eprintln!("Not purging {filename:?}: archive members are never deleted");
+                        continue;
+                    }
+                    let original = fs::canonicalize(filename).unwrap_or(filename.to_path_buf());
+                    let action = if config.trash {
+                        Action::Trash
+                    } else {
+                        Action::Delete
+                    };
+                    let entry = JournalEntry::new(action, original, kept.clone(), group);
+                    // Written ahead, so a purge is never left unrecorded.
+                    if let Some(journal) = &mut journal {
+                        journal.record(&entry)?;
+                    }
+                    let result = if config.trash {
+                        trash::delete(filename)
+                            .map_err(|e| format!("put {filename:?} in trash: {e}"))
+                    } else if filename.is_dir() {
+                        std::fs::remove_dir_all(filename)
+                            .map_err(|e| format!("delete {filename:?}: {e}"))
+                    } else {
+                        std::fs::remove_file(filename)
+                            .map_err(|e| format!("delete {filename:?}: {e}"))
+                    };
+                    match result {
+                        Ok(()) if config.trash => self.summary.trashed += 1,
+                        Ok(()) => self.summary.deleted += 1,
+                        Err(e) => {
+                            eprintln!("Failed to {e}");
+                            self.summary.failed += 1;
+                            if let Some(journal) = &mut journal {
+                                journal.record(&entry.failure(e))?;
+                            }
+                            continue;
                         }
-                    } else if let Err(e) = std::fs::remove_file(filename) {
-                        eprintln!("Failed to delete {filename:?}: {e}");
                     }
+                    self.summary.freed += size;
                 }
             }
         }
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::path::PathBuf;
 
     lazy_static::lazy_static! {
     static ref FILE1: PathBuf = PathBuf::from("test1");
@@ -136,7 +280,7 @@ mod tests {
             (&*FILE2, Mark::Purge),
             (&*FILE3, Mark::Purge),
         ];
-        let done = BasicReceiver::process_input("", &mut files);
+        let done = BasicReceiver::process_input("", &mut files, &CancellationToken::new());
         assert!(!done);
         for (file, mark) in files {
             assert!(mark == Mark::Purge, "{file:?} should be purged");
@@ -150,7 +294,7 @@ mod tests {
             (&*FILE2, Mark::Purge),
             (&*FILE3, Mark::Purge),
         ];
-        let done = BasicReceiver::process_input("all", &mut files);
+        let done = BasicReceiver::process_input("all", &mut files, &CancellationToken::new());
         assert!(done);
         for (file, mark) in files {
             assert!(mark == Mark::Keep, "{file:?} should be retained");
@@ -164,7 +308,7 @@ mod tests {
             (&*FILE2, Mark::Purge),
             (&*FILE3, Mark::Purge),
         ];
-        let done = BasicReceiver::process_input("none", &mut files);
+        let done = BasicReceiver::process_input("none", &mut files, &CancellationToken::new());
         assert!(done);
         for (file, mark) in files {
             assert!(mark == Mark::Purge, "{file:?} should be purged");
@@ -178,7 +322,7 @@ mod tests {
             (&*FILE2, Mark::Purge),
             (&*FILE3, Mark::Purge),
         ];
-        let done = BasicReceiver::process_input("2", &mut files);
+        let done = BasicReceiver::process_input("2", &mut files, &CancellationToken::new());
         assert!(done);
         for (file, mark) in files {
             if *file == *FILE2 {
@@ -188,4 +332,13 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn process_input_quit() {
+        let mut files = vec![(&*FILE1, Mark::Purge), (&*FILE2, Mark::Purge)];
+        let cancel = CancellationToken::new();
+        let done = BasicReceiver::process_input("1 quit", &mut files, &cancel);
+        assert!(done);
+        assert!(cancel.is_cancelled());
+    }
 }
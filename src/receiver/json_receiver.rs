@@ -0,0 +1,29 @@
+use crate::DupeGroup;
+use std::{io, io::Write, sync::mpsc::Receiver};
+use tracing::debug;
+
+use super::DupeGroupReceiver;
+
+/// Prints each duplicate group as a line of JSON, leaving every file in place.
+pub struct JsonReceiver {
+    rx: Receiver<DupeGroup>,
+}
+
+impl DupeGroupReceiver for JsonReceiver {
+    fn run(&mut self) -> crate::Result<()> {
+        let mut out = io::stdout().lock();
+        while let Ok(group) = self.rx.recv() {
+            debug!("{:?}", group);
+            serde_json::to_writer(&mut out, &group).map_err(io::Error::from)?;
+            writeln!(out)?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+}
+
+impl JsonReceiver {
+    pub fn new(rx: Receiver<DupeGroup>) -> Self {
+        Self { rx }
+    }
+}
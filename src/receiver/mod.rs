@@ -6,17 +6,24 @@ enum Mark {
     Keep,
 }
 
-type DupeGroup<'a> = Vec<(&'a PathBuf, Mark)>;
+type MarkedGroup<'a> = Vec<(&'a PathBuf, Mark)>;
 
 mod basic_receiver;
+mod json_receiver;
+mod progress_bar;
+mod similarity_receiver;
+mod unique_receiver;
 
 pub use basic_receiver::BasicReceiver;
+pub use json_receiver::JsonReceiver;
+pub use similarity_receiver::SimilarityReceiver;
+pub use unique_receiver::UniqueReceiver;
 
 pub trait DupeGroupReceiver: Send {
-    fn run(&mut self) -> Result<(), std::io::Error>;
+    fn run(&mut self) -> crate::Result<()>;
 }
 
-fn mark_group(files: &mut DupeGroup, purge: Mark) {
+fn mark_group(files: &mut MarkedGroup, purge: Mark) {
     for file in files {
         file.1 = purge;
     }
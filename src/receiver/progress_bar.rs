@@ -0,0 +1,87 @@
+use crate::{ProgressEvent, ProgressStage};
+use num_format::{Locale, ToFormattedString};
+use std::{io, io::Write, time::Duration};
+
+const WIDTH: usize = 30;
+
+/// Single line progress display on stderr, redrawn in place.
+#[derive(Debug, Default)]
+pub(crate) struct ProgressBar {
+    drawn: bool,
+}
+
+impl ProgressBar {
+    pub fn draw(&mut self, event: &ProgressEvent) {
+        let mut err = io::stderr().lock();
+        // A failed redraw only loses the progress display.
+        let _ = write!(err, "\r{}\x1b[K", render(event)).and_then(|()| err.flush());
+        self.drawn = true;
+    }
+
+    /// Remove the bar, leaving the cursor at the start of the line.
+    pub fn clear(&mut self) {
+        if self.drawn {
+            let mut err = io::stderr().lock();
+            let _ = write!(err, "\r\x1b[K").and_then(|()| err.flush());
+            self.drawn = false;
+        }
+    }
+}
+
+fn render(event: &ProgressEvent) -> String {
+    let files = event.files_discovered.to_formatted_string(&Locale::en_GB);
+    match event.stage {
+        ProgressStage::Counting => format!("Counting sizes: {files} files found"),
+        ProgressStage::Walking => format!("Scanning: {files} files found"),
+        ProgressStage::Bucketing => format!("Sizing {files} files"),
+        ProgressStage::Comparing | ProgressStage::Done => {
+            let filled = (event.buckets_done * WIDTH)
+                .checked_div(event.buckets_total)
+                .unwrap_or(WIDTH);
+            let eta = event
+                .eta
+                .map(|eta| format!(", ETA {}", clock(eta)))
+                .unwrap_or_default();
+            format!(
+                "[{}{}] {}/{} groups, {} bytes read{}",
+                "#".repeat(filled),
+                " ".repeat(WIDTH - filled),
+                event.buckets_done.to_formatted_string(&Locale::en_GB),
+                event.buckets_total.to_formatted_string(&Locale::en_GB),
+                event.bytes_hashed.to_formatted_string(&Locale::en_GB),
+                eta
+            )
+        }
+    }
+}
+
+fn clock(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 3600 {
+        format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+    } else {
+        format!("{}:{:02}", secs / 60, secs % 60)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_comparing() {
+        let event = ProgressEvent {
+            stage: ProgressStage::Comparing,
+            files_discovered: 1200,
+            bytes_hashed: 1_048_576,
+            buckets_done: 10,
+            buckets_total: 40,
+            elapsed: Duration::from_secs(5),
+            eta: Some(Duration::from_secs(3725)),
+        };
+        assert_eq!(
+            render(&event),
+            "[#######                       ] 10/40 groups, 1,048,576 bytes read, ETA 1:02:05"
+        );
+    }
+}
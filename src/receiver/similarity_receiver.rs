@@ -0,0 +1,69 @@
+use crate::SimilarMessage;
+use std::{io, io::Write, sync::mpsc::Receiver};
+use tracing::debug;
+
+use super::DupeGroupReceiver;
+
+/// Lists clusters of near-duplicate files. Near-duplicates are only ever
+/// reported: nothing is purged.
+pub struct SimilarityReceiver {
+    rx: Receiver<SimilarMessage>,
+}
+
+impl DupeGroupReceiver for SimilarityReceiver {
+    fn run(&mut self) -> crate::Result<()> {
+        while let Ok(message) = self.rx.recv() {
+            debug!("{:?}", message);
+            Self::handle_cluster(&message, &mut io::stdout())?;
+        }
+        Ok(())
+    }
+}
+
+impl SimilarityReceiver {
+    pub fn new(rx: Receiver<SimilarMessage>) -> Self {
+        Self { rx }
+    }
+
+    fn handle_cluster(message: &SimilarMessage, out: &mut impl Write) -> io::Result<()> {
+        writeln!(
+            out,
+            "({}/{}) {} similar files:",
+            message.id + 1,
+            message.total,
+            message.filenames.len()
+        )?;
+        for (id, filename) in message.filenames.iter().enumerate() {
+            writeln!(out, "[{}] {:?}", id + 1, filename)?;
+        }
+        for (a, b, score) in &message.scores {
+            writeln!(out, "    [{}] ~ [{}]: {}% similar", a + 1, b + 1, score)?;
+        }
+        writeln!(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn handle_cluster() {
+        let message = SimilarMessage {
+            id: 0,
+            total: 1,
+            filenames: vec![
+                PathBuf::from("contract.txt"),
+                PathBuf::from("contract-v2.txt"),
+            ],
+            scores: vec![(0, 1, 93)],
+        };
+        let mut out = Vec::new();
+        SimilarityReceiver::handle_cluster(&message, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "(1/1) 2 similar files:\n[1] \"contract.txt\"\n[2] \"contract-v2.txt\"\n    [1] ~ [2]: 93% similar\n\n"
+        );
+    }
+}
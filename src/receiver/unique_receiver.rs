@@ -0,0 +1,31 @@
+use crate::DupeGroup;
+use std::{io, io::Write, sync::mpsc::Receiver};
+use tracing::debug;
+
+use super::DupeGroupReceiver;
+
+/// Lists files with no copy, one quoted and escaped path per line, as the
+/// other receivers print them. Nothing is purged.
+pub struct UniqueReceiver {
+    rx: Receiver<DupeGroup>,
+}
+
+impl DupeGroupReceiver for UniqueReceiver {
+    fn run(&mut self) -> crate::Result<()> {
+        let mut out = io::stdout().lock();
+        while let Ok(group) = self.rx.recv() {
+            debug!("{:?}", group);
+            for filename in group.filenames() {
+                writeln!(out, "{filename:?}")?;
+            }
+        }
+        out.flush()?;
+        Ok(())
+    }
+}
+
+impl UniqueReceiver {
+    pub fn new(rx: Receiver<DupeGroup>) -> Self {
+        Self { rx }
+    }
+}
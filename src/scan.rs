@@ -0,0 +1,480 @@
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use crate::error::Result;
+use crate::{
+    CancellationToken, Config, DupeGroup, DupeScanner, ExactGroupComparator, GroupChanges,
+    GroupComparator, IoStrategy, ProgressEvent, ReadOrder,
+};
+
+/// Library entry point, for embedding duplicate detection without going
+/// through the command line:
+///
+/// ```no_run
+/// use fdupes::Scan;
+///
+/// for group in Scan::builder().root("/srv/backups").min_size(1024).run()? {
+///     println!("{:?}", group?.filenames().collect::<Vec<_>>());
+/// }
+/// # Ok::<(), fdupes::FdupesError>(())
+/// ```
+pub struct Scan;
+
+impl Scan {
+    pub fn builder() -> ScanBuilder {
+        ScanBuilder::default()
+    }
+}
+
+/// Configures a [`Scan`]; files are compared exactly unless other
+/// comparators are added.
+#[derive(Default)]
+pub struct ScanBuilder {
+    config: Config,
+    comparators: Vec<Box<dyn GroupComparator>>,
+    cancel: CancellationToken,
+    progress: Option<Sender<ProgressEvent>>,
+}
+
+impl ScanBuilder {
+    pub fn root(mut self, root: impl Into<PathBuf>) -> Self {
+        self.config.roots.push(root.into());
+        self
+    }
+
+    pub fn roots<I, S>(mut self, roots: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<PathBuf>,
+    {
+        self.config.roots.extend(roots.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn non_recursive(mut self, non_recursive: bool) -> Self {
+        self.config.non_recursive = non_recursive;
+        self
+    }
+
+    pub fn min_size(mut self, min_size: u64) -> Self {
+        self.config.min_size = min_size;
+        self
+    }
+
+    pub fn archives(mut self, archives: bool) -> Self {
+        self.config.archives = archives;
+        self
+    }
+
+    /// Most files held open at once while comparing a group; larger groups
+    /// are compared in batches.
+    pub fn max_open(mut self, max_open: usize) -> Self {
+        self.config.max_open = max_open;
+        self
+    }
+
+    /// Blocks compared from the middle of same-sized files, after the first
+    /// and last, before they are read in full.
+    pub fn samples(mut self, samples: usize) -> Self {
+        self.config.samples = samples;
+        self
+    }
+
+    /// How files are read while comparing them.
+    pub fn io(mut self, io: IoStrategy) -> Self {
+        self.config.io = io;
+        self
+    }
+
+    /// Order in which each group's files are first read; sorting by disk
+    /// position seeks less on spinning disks.
+    pub fn read_order(mut self, read_order: ReadOrder) -> Self {
+        self.config.read_order = read_order;
+        self
+    }
+
+    /// Most bytes read per second while comparing files; `None` for no limit.
+    pub fn max_read_rate(mut self, bytes_per_sec: Option<u64>) -> Self {
+        self.config.max_read_rate = bytes_per_sec;
+        self
+    }
+
+    /// Scan at idle I/O priority and lowest CPU priority, where supported.
+    pub fn low_priority(mut self, low_priority: bool) -> Self {
+        self.config.low_priority = low_priority;
+        self
+    }
+
+    /// Save progress to `path` as the scan goes, removing it once finished.
+    pub fn checkpoint(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.checkpoint = Some(path.into());
+        self
+    }
+
+    /// Continue from the checkpoint, when there is one of the same scan.
+    pub fn resume(mut self, resume: bool) -> Self {
+        self.config.resume = resume;
+        self
+    }
+
+    /// Report files under the first root with no copy under the others, as
+    /// single-file groups, instead of duplicates.
+    pub fn unique(mut self, unique: bool) -> Self {
+        self.config.unique = unique;
+        self
+    }
+
+    /// Keep every file's metadata and group at `path` between scans, so
+    /// later scans only compare the files changed since. How the groups
+    /// found differ from the last scan's is then given by
+    /// [`ScanIter::changes`].
+    pub fn index(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.index = Some(path.into());
+        self
+    }
+
+    /// Walk the roots twice, so memory grows with candidate duplicates
+    /// rather than every file. Ignored when scanning directories.
+    pub fn low_memory(mut self, low_memory: bool) -> Self {
+        self.config.low_memory = low_memory;
+        self
+    }
+
+    pub fn dirs(mut self, dirs: bool) -> Self {
+        self.config.dirs = dirs;
+        self
+    }
+
+    pub fn dir_names(mut self, dir_names: bool) -> Self {
+        self.config.dir_names = dir_names;
+        self
+    }
+
+    pub fn comparator(mut self, comparator: impl GroupComparator + 'static) -> Self {
+        self.comparators.push(Box::new(comparator));
+        self
+    }
+
+    /// Cancelling `cancel` ends the scan early, yielding `FdupesError::Cancelled`.
+    pub fn cancellation(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// Subscribe to progress events, sent separately from the groups found.
+    pub fn progress(mut self, tx: Sender<ProgressEvent>) -> Self {
+        self.progress = Some(tx);
+        self
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Start scanning in the background, yielding groups as they are found.
+    pub fn run(self) -> Result<ScanIter> {
+        let (tx, rx) = mpsc::channel();
+        let mut comparators = self.comparators;
+        if comparators.is_empty() {
+            comparators.push(Box::new(
+                ExactGroupComparator::new().with_io(self.config.io),
+            ));
+        }
+        let index = self.config.index.clone();
+        let mut scanner = DupeScanner::new(tx, Arc::new(self.config), comparators)?
+            .with_cancellation(self.cancel);
+        if let Some(progress) = self.progress {
+            scanner = scanner.with_progress(progress);
+        }
+        let handle = thread::spawn(move || match index {
+            Some(index) => scanner.find_groups_incremental(&index).map(Some),
+            None => scanner.find_groups().map(|()| None),
+        });
+        Ok(ScanIter {
+            rx,
+            handle: Some(handle),
+            changes: None,
+        })
+    }
+
+    /// Scan, passing each group to `callback` as it is found.
+    pub fn run_with(self, mut callback: impl FnMut(DupeGroup)) -> Result<()> {
+        for group in self.run()? {
+            callback(group?);
+        }
+        Ok(())
+    }
+}
+
+/// Duplicate groups from a running [`Scan`]. Should the scan fail, its error
+/// is yielded last.
+pub struct ScanIter {
+    rx: Receiver<DupeGroup>,
+    handle: Option<JoinHandle<Result<Option<GroupChanges>>>>,
+    changes: Option<GroupChanges>,
+}
+
+impl ScanIter {
+    /// For a scan with an [index](ScanBuilder::index), the groups new or
+    /// resolved since the last scan; available once every group has been
+    /// yielded.
+    pub fn changes(&self) -> Option<&GroupChanges> {
+        self.changes.as_ref()
+    }
+}
+
+impl Iterator for ScanIter {
+    type Item = Result<DupeGroup>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.rx.recv() {
+            Ok(group) => Some(Ok(group)),
+            Err(_) => match self.handle.take()?.join() {
+                Ok(Ok(changes)) => {
+                    self.changes = changes;
+                    None
+                }
+                Ok(Err(e)) => Some(Err(e)),
+                Err(panic) => std::panic::resume_unwind(panic),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Scan;
+    use crate::{CancellationToken, FdupesError, ReadOrder};
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    lazy_static::lazy_static! {
+    static ref SCRATCH: &'static Path = Path::new("test_data/scan_scratch");
+    static ref LOW_MEMORY_SCRATCH: &'static Path = Path::new("test_data/scan_low_memory_scratch");
+    static ref ORDER_SCRATCH: &'static Path = Path::new("test_data/scan_order_scratch");
+    static ref UNIQUE_SCRATCH: &'static Path = Path::new("test_data/scan_unique_scratch");
+    static ref INDEX_SCRATCH: &'static Path = Path::new("test_data/scan_index_scratch");
+    static ref IMAGE_SCRATCH: &'static Path = Path::new("test_data/scan_image_scratch");
+    static ref TEST_DATA1: &'static Path = Path::new("test_data/file1.txt");
+    static ref TEST_DATA2: &'static Path = Path::new("test_data/file2.txt");
+    }
+
+    #[test]
+    fn builder() {
+        fs::create_dir_all(*SCRATCH).unwrap();
+        fs::copy(*TEST_DATA1, SCRATCH.join("a.txt")).unwrap();
+        fs::copy(*TEST_DATA1, SCRATCH.join("b.txt")).unwrap();
+        fs::copy(*TEST_DATA2, SCRATCH.join("c.txt")).unwrap();
+
+        let groups = Scan::builder()
+            .root(*SCRATCH)
+            .non_recursive(true)
+            .run()
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        fs::remove_dir_all(*SCRATCH).unwrap();
+        assert_eq!(groups.len(), 1);
+        let mut filenames = groups[0].filenames().cloned().collect::<Vec<_>>();
+        filenames.sort();
+        assert_eq!(
+            filenames,
+            vec![SCRATCH.join("a.txt"), SCRATCH.join("b.txt")]
+        );
+        assert_eq!(groups[0].comparator, "exact");
+    }
+
+    #[test]
+    fn low_memory() {
+        fs::create_dir_all(*LOW_MEMORY_SCRATCH).unwrap();
+        fs::copy(*TEST_DATA1, LOW_MEMORY_SCRATCH.join("a.txt")).unwrap();
+        fs::copy(*TEST_DATA1, LOW_MEMORY_SCRATCH.join("b.txt")).unwrap();
+        fs::copy(*TEST_DATA2, LOW_MEMORY_SCRATCH.join("c.txt")).unwrap();
+        fs::write(LOW_MEMORY_SCRATCH.join("d.txt"), "unique").unwrap();
+
+        let groups = Scan::builder()
+            .root(*LOW_MEMORY_SCRATCH)
+            .non_recursive(true)
+            .low_memory(true)
+            .run()
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        fs::remove_dir_all(*LOW_MEMORY_SCRATCH).unwrap();
+        assert_eq!(groups.len(), 1);
+        let mut filenames = groups[0].filenames().cloned().collect::<Vec<_>>();
+        filenames.sort();
+        assert_eq!(
+            filenames,
+            vec![
+                LOW_MEMORY_SCRATCH.join("a.txt"),
+                LOW_MEMORY_SCRATCH.join("b.txt")
+            ]
+        );
+    }
+
+    #[test]
+    fn roots_in_order() {
+        let roots = ["c", "a", "b"].map(|root| ORDER_SCRATCH.join(root));
+        for root in &roots {
+            fs::create_dir_all(root).unwrap();
+            fs::copy(*TEST_DATA1, root.join("file1.txt")).unwrap();
+            fs::copy(*TEST_DATA2, root.join("file2.txt")).unwrap();
+        }
+
+        for read_order in [ReadOrder::Walk, ReadOrder::Inode, ReadOrder::Extent] {
+            let groups = Scan::builder()
+                .roots(roots.clone())
+                .non_recursive(true)
+                .read_order(read_order)
+                .run()
+                .unwrap()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+
+            assert_eq!(groups.len(), 2);
+            for group in groups {
+                let roots = group
+                    .files
+                    .iter()
+                    .map(|file| file.root.clone().unwrap())
+                    .collect::<Vec<_>>();
+                assert_eq!(
+                    roots,
+                    ["c", "a", "b"].map(|root| ORDER_SCRATCH.join(root)),
+                    "{read_order:?}"
+                );
+            }
+        }
+        fs::remove_dir_all(*ORDER_SCRATCH).unwrap();
+    }
+
+    #[test]
+    fn images_skip_exact_copies() {
+        use crate::{ExactGroupComparator, ImageGroupComparator};
+        use image::{imageops::FilterType, DynamicImage, RgbImage};
+
+        fs::create_dir_all(*IMAGE_SCRATCH).unwrap();
+        let original = DynamicImage::ImageRgb8(RgbImage::from_fn(128, 96, |x, y| {
+            image::Rgb([(x * 2) as u8, (y * 2) as u8, ((x + y) % 256) as u8])
+        }));
+        original.save(IMAGE_SCRATCH.join("a.png")).unwrap();
+        fs::copy(IMAGE_SCRATCH.join("a.png"), IMAGE_SCRATCH.join("b.png")).unwrap();
+        original
+            .resize_exact(64, 48, FilterType::Triangle)
+            .save(IMAGE_SCRATCH.join("c.png"))
+            .unwrap();
+
+        let groups = Scan::builder()
+            .root(*IMAGE_SCRATCH)
+            .non_recursive(true)
+            .comparator(ExactGroupComparator::new())
+            .comparator(ImageGroupComparator::new())
+            .run()
+            .unwrap()
+            .map(|group| {
+                group.map(|group| {
+                    let mut filenames = group.filenames().cloned().collect::<Vec<_>>();
+                    filenames.sort();
+                    (group.comparator, filenames)
+                })
+            })
+            .collect::<Result<BTreeMap<_, _>, _>>()
+            .unwrap();
+
+        fs::remove_dir_all(*IMAGE_SCRATCH).unwrap();
+        // The exact copy is reported once, and the resized copy only with the original.
+        assert_eq!(
+            groups,
+            BTreeMap::from([
+                (
+                    "exact".to_owned(),
+                    vec![IMAGE_SCRATCH.join("a.png"), IMAGE_SCRATCH.join("b.png")]
+                ),
+                (
+                    "image".to_owned(),
+                    vec![IMAGE_SCRATCH.join("a.png"), IMAGE_SCRATCH.join("c.png")]
+                ),
+            ])
+        );
+    }
+
+    #[test]
+    fn unique() {
+        let (laptop, backup) = (UNIQUE_SCRATCH.join("laptop"), UNIQUE_SCRATCH.join("backup"));
+        fs::create_dir_all(&laptop).unwrap();
+        fs::create_dir_all(&backup).unwrap();
+        fs::copy(*TEST_DATA1, laptop.join("a.txt")).unwrap();
+        fs::copy(*TEST_DATA2, laptop.join("b.txt")).unwrap();
+        fs::write(laptop.join("c.txt"), "only on the laptop").unwrap();
+        fs::write(laptop.join("d.txt"), "only on the laptop").unwrap();
+        fs::copy(*TEST_DATA1, backup.join("a.txt")).unwrap();
+
+        let unique = |roots: Vec<PathBuf>| {
+            let mut filenames = Scan::builder()
+                .roots(roots)
+                .unique(true)
+                .run()
+                .unwrap()
+                .map(|group| group.unwrap().filenames().cloned().collect::<Vec<_>>())
+                .collect::<Vec<_>>();
+            filenames.sort();
+            filenames
+        };
+        let not_backed_up = unique(vec![laptop.clone(), backup.clone()]);
+        let without_copies = unique(vec![laptop.clone()]);
+
+        fs::remove_dir_all(*UNIQUE_SCRATCH).unwrap();
+        assert_eq!(
+            not_backed_up,
+            ["b.txt", "c.txt", "d.txt"].map(|name| vec![laptop.join(name)])
+        );
+        assert_eq!(
+            without_copies,
+            ["a.txt", "b.txt"].map(|name| vec![laptop.join(name)])
+        );
+    }
+
+    #[test]
+    fn cancelled() {
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let results = Scan::builder()
+            .root("test_data")
+            .cancellation(cancel)
+            .run()
+            .unwrap()
+            .collect::<Vec<_>>();
+        assert!(matches!(results[..], [Err(FdupesError::Cancelled)]));
+    }
+
+    #[test]
+    fn index_changes() {
+        fs::create_dir_all(*INDEX_SCRATCH).unwrap();
+        fs::copy(*TEST_DATA1, INDEX_SCRATCH.join("a.txt")).unwrap();
+        fs::copy(*TEST_DATA1, INDEX_SCRATCH.join("b.txt")).unwrap();
+        let index = INDEX_SCRATCH.with_extension("json");
+
+        let scan = || {
+            let mut groups = Scan::builder()
+                .root(*INDEX_SCRATCH)
+                .index(&index)
+                .run()
+                .unwrap();
+            assert_eq!(groups.by_ref().filter_map(Result::ok).count(), 1);
+            groups.changes().cloned().unwrap()
+        };
+        let first = scan();
+        let second = scan();
+        fs::remove_dir_all(*INDEX_SCRATCH).unwrap();
+        fs::remove_file(&index).unwrap();
+
+        assert_eq!((first.new.len(), first.resolved.len()), (1, 0));
+        assert_eq!(second, Default::default());
+    }
+}
@@ -0,0 +1,257 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter},
+    path::{Path, PathBuf},
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::dupegroup::lossless;
+use crate::error::{FdupesError, Result};
+use crate::{Config, DupeGroup};
+
+/// Layout version of [`Checkpoint`], bumped on incompatible changes.
+const CHECKPOINT_VERSION: u32 = 2;
+
+/// Saved state of an interrupted scan: the files the walk found, as they
+/// were bucketed, and the groups of each bucket already compared.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Checkpoint {
+    version: u32,
+    scan: ScanKey,
+    buckets: Vec<Bucket>,
+    completed: Vec<Completed>,
+}
+
+/// Everything that decides a scan's results. A checkpoint only resumes a
+/// scan with the same key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct ScanKey {
+    roots: Vec<StoredPath>,
+    non_recursive: bool,
+    min_size: u64,
+    decompress: bool,
+    archives: bool,
+    images: bool,
+    image_distance: u32,
+    ignore_metadata: bool,
+    comparators: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) struct StoredPath(#[serde(with = "lossless")] pub PathBuf);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Bucket {
+    size: u64,
+    comparator: String,
+    files: Vec<StoredPath>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Completed {
+    size: u64,
+    comparator: String,
+    groups: Vec<SavedGroup>,
+}
+
+/// A group found in a compared bucket, with the digest of its content.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct SavedGroup {
+    pub files: Vec<StoredPath>,
+    pub digest: Option<String>,
+}
+
+impl From<&DupeGroup> for SavedGroup {
+    fn from(group: &DupeGroup) -> Self {
+        Self {
+            files: group.filenames().cloned().map(StoredPath).collect(),
+            digest: group.digest.clone(),
+        }
+    }
+}
+
+impl ScanKey {
+    pub fn new<'a>(config: &Config, comparators: impl IntoIterator<Item = &'a String>) -> Self {
+        Self {
+            roots: config.roots.iter().cloned().map(StoredPath).collect(),
+            non_recursive: config.non_recursive,
+            min_size: config.min_size,
+            decompress: config.decompress,
+            archives: config.archives,
+            images: config.images,
+            image_distance: config.image_distance,
+            ignore_metadata: config.ignore_metadata,
+            comparators: comparators.into_iter().cloned().collect(),
+        }
+    }
+}
+
+impl Checkpoint {
+    pub fn new(scan: ScanKey, buckets: &BTreeMap<(u64, String), Vec<PathBuf>>) -> Self {
+        Self {
+            version: CHECKPOINT_VERSION,
+            scan,
+            buckets: buckets
+                .iter()
+                .map(|((size, comparator), files)| Bucket {
+                    size: *size,
+                    comparator: comparator.clone(),
+                    files: files.iter().cloned().map(StoredPath).collect(),
+                })
+                .collect(),
+            completed: Vec::new(),
+        }
+    }
+
+    /// Whether this checkpoint was taken of a scan with `scan`'s key.
+    pub fn is_for(&self, scan: &ScanKey) -> bool {
+        self.version == CHECKPOINT_VERSION && self.scan == *scan
+    }
+
+    pub fn buckets(&self) -> BTreeMap<(u64, String), Vec<PathBuf>> {
+        self.buckets
+            .iter()
+            .map(|bucket| {
+                let files = bucket.files.iter().map(|file| file.0.clone()).collect();
+                ((bucket.size, bucket.comparator.clone()), files)
+            })
+            .collect()
+    }
+
+    /// The groups found in the bucket `key`, if it was already compared.
+    pub fn completed(&self, (size, comparator): &(u64, String)) -> Option<&[SavedGroup]> {
+        self.completed
+            .iter()
+            .find(|done| done.size == *size && done.comparator == *comparator)
+            .map(|done| done.groups.as_slice())
+    }
+
+    /// Record the bucket `key` as compared, finding `groups`.
+    pub fn complete(&mut self, (size, comparator): (u64, String), groups: &[DupeGroup]) {
+        self.completed.push(Completed {
+            size,
+            comparator,
+            groups: groups.iter().map(SavedGroup::from).collect(),
+        });
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        load(path)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        save(self, path)
+    }
+}
+
+pub(super) fn load<T: DeserializeOwned>(path: &Path) -> Result<T> {
+    let file = File::open(path).map_err(|e| FdupesError::io(path, e))?;
+    serde_json::from_reader(BufReader::new(file))
+        .map_err(|e| FdupesError::io(path, io::Error::from(e)))
+}
+
+/// Write `value` to `path` as JSON, replacing any earlier file only once it
+/// is complete.
+pub(super) fn save(value: &impl Serialize, path: &Path) -> Result<()> {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+    let write = || -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(&partial)?);
+        serde_json::to_writer(&mut writer, value).map_err(io::Error::from)?;
+        writer.into_inner().map_err(io::Error::from)?.sync_all()?;
+        fs::rename(&partial, path)
+    };
+    write().map_err(|e| FdupesError::io(path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Checkpoint, ScanKey};
+    use crate::{Config, DupeGroup, Scan};
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    lazy_static::lazy_static! {
+    static ref SCRATCH: &'static Path = Path::new("test_data/checkpoint_scratch");
+    static ref STATE: &'static Path = Path::new("test_data/checkpoint_scratch.json");
+    static ref TEST_DATA1: &'static Path = Path::new("test_data/file1.txt");
+    }
+
+    fn scan(min_size: u64) -> Vec<(Option<String>, Vec<PathBuf>)> {
+        Scan::builder()
+            .root(*SCRATCH)
+            .non_recursive(true)
+            .min_size(min_size)
+            .checkpoint(*STATE)
+            .resume(true)
+            .run()
+            .unwrap()
+            .map(|group| {
+                let group = group.unwrap();
+                (group.digest.clone(), group.filenames().cloned().collect())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn resume() {
+        fs::create_dir_all(*SCRATCH).unwrap();
+        let files = ["a", "b", "c", "d"].map(|name| SCRATCH.join(name));
+        fs::copy(*TEST_DATA1, &files[0]).unwrap();
+        fs::copy(*TEST_DATA1, &files[1]).unwrap();
+        fs::write(&files[2], "a different size").unwrap();
+        fs::write(&files[3], "a different size").unwrap();
+        let walked = files
+            .iter()
+            .map(|file| (file.metadata().unwrap().len(), file.clone()))
+            .collect::<Vec<_>>();
+        let mut buckets = BTreeMap::new();
+        for (size, file) in &walked {
+            buckets
+                .entry((*size, "exact".to_owned()))
+                .or_insert_with(Vec::new)
+                .push(file.clone());
+        }
+        let config = Config {
+            roots: vec![SCRATCH.to_path_buf()],
+            non_recursive: true,
+            ..Config::default()
+        };
+        let key = ScanKey::new(&config, [&"exact".to_owned()]);
+        let mut checkpoint = Checkpoint::new(key, &buckets);
+        let saved = DupeGroup::new(
+            0,
+            2,
+            walked[0].0,
+            "exact",
+            Some("sha256:saved".to_owned()),
+            files[..2].to_vec(),
+            &config.roots,
+        );
+        checkpoint.complete((walked[0].0, "exact".to_owned()), &[saved]);
+
+        checkpoint.save(&STATE).unwrap();
+        assert_eq!(Checkpoint::load(&STATE).unwrap(), checkpoint);
+        // The compared bucket's group is sent as saved, without reading its
+        // files again, then the bucket left is compared. A finished scan
+        // leaves no checkpoint behind.
+        let resumed = scan(0);
+        assert_eq!(
+            resumed[0],
+            (Some("sha256:saved".to_owned()), files[..2].to_vec())
+        );
+        assert_eq!(resumed[1].1, files[2..].to_vec());
+        assert_eq!(resumed.len(), 2);
+        assert!(!STATE.exists());
+
+        // A checkpoint of a scan with other options is ignored.
+        checkpoint.save(&STATE).unwrap();
+        assert_eq!(scan(1).len(), 2);
+
+        fs::remove_dir_all(*SCRATCH).unwrap();
+    }
+}
@@ -0,0 +1,291 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use walkdir::DirEntry;
+
+type Entry = (Option<OsString>, usize);
+
+#[derive(Debug, Default)]
+struct Dir {
+    entries: Vec<Entry>,
+    size: u64,
+}
+
+/// An entry found under the roots.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Node {
+    File(u64),
+    Dir,
+    /// A symlink or special file, or an entry which could not be read.
+    Other,
+}
+
+/// The node for a walked `entry`, without following symlinks.
+pub(crate) fn node(entry: &DirEntry) -> Node {
+    let file_type = entry.file_type();
+    if file_type.is_dir() {
+        Node::Dir
+    } else if file_type.is_file() {
+        entry
+            .metadata()
+            .map_or(Node::Other, |m| Node::File(m.len()))
+    } else {
+        Node::Other
+    }
+}
+
+/// Find directories under `roots` with identical contents, bottom-up.
+///
+/// `content` maps each file to the id of the group of files holding the same
+/// bytes; any other file, and any [`Node::Other`], is unique, so neither its
+/// directory nor any above it can match another. Each directory is interned
+/// by its sorted child entries (optionally including names), so two
+/// directories share an id exactly when their whole subtrees match. Empty
+/// directories are not reported by themselves, and groups nested within a
+/// larger duplicate group are left out. Returns (total size, directories)
+/// pairs, largest first.
+pub(crate) fn duplicate_dirs(
+    roots: &[PathBuf],
+    entries: &[(PathBuf, Node)],
+    content: &HashMap<PathBuf, usize>,
+    include_names: bool,
+) -> Vec<(u64, Vec<PathBuf>)> {
+    let mut next_id = content.values().max().map_or(0, |id| id + 1);
+    let mut unique = || {
+        next_id += 1;
+        next_id - 1
+    };
+    let mut dirs: BTreeMap<PathBuf, Dir> = BTreeMap::new();
+    for (path, node) in entries {
+        let root = match roots.iter().find(|root| path.starts_with(root)) {
+            Some(root) => root,
+            None => continue,
+        };
+        for dir in path
+            .ancestors()
+            .skip(1)
+            .take_while(|dir| dir.starts_with(root))
+        {
+            dirs.entry(dir.to_owned()).or_default();
+        }
+        let (id, size) = match node {
+            Node::Dir => {
+                dirs.entry(path.clone()).or_default();
+                continue;
+            }
+            Node::File(size) => (
+                content.get(path).copied().unwrap_or_else(&mut unique),
+                *size,
+            ),
+            // A directory which could not be read differs from every other.
+            Node::Other if dirs.contains_key(path) => {
+                if let Some(dir) = dirs.get_mut(path) {
+                    dir.entries.push((None, unique()));
+                }
+                continue;
+            }
+            Node::Other => (unique(), 0),
+        };
+        if let Some(parent) = path.parent().and_then(|parent| dirs.get_mut(parent)) {
+            parent.entries.push((name(path, include_names), id));
+            parent.size += size;
+        }
+    }
+
+    let mut order = dirs.keys().cloned().collect::<Vec<_>>();
+    order.sort_by_key(|dir| std::cmp::Reverse(dir.components().count()));
+
+    let mut interned: HashMap<Vec<Entry>, usize> = HashMap::new();
+    let mut by_id: BTreeMap<usize, (u64, Vec<PathBuf>)> = BTreeMap::new();
+    for dir in order {
+        let Dir { mut entries, size } = dirs.remove(&dir).unwrap_or_default();
+        entries.sort();
+        let empty = entries.is_empty();
+        let id = *interned.entry(entries).or_insert_with(&mut unique);
+        if let Some(parent) = dir.parent().and_then(|parent| dirs.get_mut(parent)) {
+            parent.entries.push((name(&dir, include_names), id));
+            parent.size += size;
+        }
+        if !empty {
+            let group = by_id.entry(id).or_insert_with(|| (size, Vec::new()));
+            group.1.push(dir);
+        }
+    }
+
+    let groups = by_id
+        .into_values()
+        .filter(|(_, dirs)| dirs.len() > 1)
+        .collect::<Vec<_>>();
+    let duplicated = groups
+        .iter()
+        .flat_map(|(_, dirs)| dirs.iter().map(PathBuf::as_path))
+        .collect::<HashSet<&Path>>();
+    let mut groups = groups
+        .iter()
+        .filter(|(_, dirs)| {
+            !dirs.iter().all(|dir| {
+                dir.parent()
+                    .is_some_and(|parent| duplicated.contains(parent))
+            })
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+    groups.sort_by_key(|(size, _)| std::cmp::Reverse(*size));
+    groups
+}
+
+fn name(path: &Path, include_names: bool) -> Option<OsString> {
+    if include_names {
+        path.file_name().map(|name| name.to_owned())
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{duplicate_dirs, Node};
+    use crate::{DecompressGroupComparator, Scan};
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    lazy_static::lazy_static! {
+    static ref SCRATCH: &'static Path = Path::new("test_data/dirtree_scratch");
+    static ref DECOMPRESS_SCRATCH: &'static Path = Path::new("test_data/dirtree_decompress_scratch");
+    static ref SHALLOW_SCRATCH: &'static Path = Path::new("test_data/dirtree_shallow_scratch");
+    static ref TEST_DATA1: &'static Path = Path::new("test_data/file1.txt");
+    }
+
+    fn files(
+        paths: &[(&str, u64, Option<usize>)],
+    ) -> (Vec<(PathBuf, Node)>, HashMap<PathBuf, usize>) {
+        let files = paths
+            .iter()
+            .map(|(path, size, _)| (PathBuf::from(path), Node::File(*size)))
+            .collect();
+        let content = paths
+            .iter()
+            .filter_map(|(path, _, id)| Some((PathBuf::from(path), (*id)?)))
+            .collect();
+        (files, content)
+    }
+
+    #[test]
+    fn identical_trees() {
+        let (files, content) = files(&[
+            ("r/a/x.txt", 10, Some(0)),
+            ("r/a/sub/y.txt", 20, Some(1)),
+            ("r/b/x.txt", 10, Some(0)),
+            ("r/b/sub/y.txt", 20, Some(1)),
+            ("r/c/x.txt", 10, Some(0)),
+            ("r/c/z.txt", 30, None),
+        ]);
+        let groups = duplicate_dirs(&[PathBuf::from("r")], &files, &content, false);
+        assert_eq!(
+            groups,
+            vec![(30, vec![PathBuf::from("r/a"), PathBuf::from("r/b")])]
+        );
+    }
+
+    #[test]
+    fn names() {
+        let (files, content) =
+            files(&[("r/a/x.txt", 10, Some(0)), ("r/b/renamed.txt", 10, Some(0))]);
+        let roots = [PathBuf::from("r")];
+        assert_eq!(
+            duplicate_dirs(&roots, &files, &content, false),
+            vec![(10, vec![PathBuf::from("r/a"), PathBuf::from("r/b")])]
+        );
+        assert!(duplicate_dirs(&roots, &files, &content, true).is_empty());
+    }
+
+    #[test]
+    fn unread_entries() {
+        // Neither small file was compared, nor can a symlink be.
+        let (mut files, content) = files(&[
+            ("r/a/big", 10, Some(0)),
+            ("r/a/small", 1, None),
+            ("r/b/big", 10, Some(0)),
+            ("r/b/small", 1, None),
+            ("r/c/big", 10, Some(0)),
+            ("r/d/big", 10, Some(0)),
+        ]);
+        let roots = [PathBuf::from("r")];
+        assert_eq!(
+            duplicate_dirs(&roots, &files, &content, false),
+            vec![(10, vec![PathBuf::from("r/c"), PathBuf::from("r/d")])]
+        );
+        files.push((PathBuf::from("r/d/link"), Node::Other));
+        assert!(duplicate_dirs(&roots, &files, &content, false).is_empty());
+    }
+
+    #[test]
+    fn files_below_min_size() {
+        for (dir, small) in [("A", "one"), ("B", "two")] {
+            let dir = SCRATCH.join(dir);
+            fs::create_dir_all(&dir).unwrap();
+            fs::copy(*TEST_DATA1, dir.join("big")).unwrap();
+            fs::write(dir.join("small"), small).unwrap();
+        }
+        let groups = Scan::builder()
+            .root(*SCRATCH)
+            .dirs(true)
+            .min_size(100)
+            .run()
+            .unwrap()
+            .map(|group| group.unwrap())
+            .collect::<Vec<_>>();
+        fs::remove_dir_all(*SCRATCH).unwrap();
+
+        // Only the big files are reported, never their differing directories.
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].comparator, "exact");
+    }
+
+    #[test]
+    fn decompressed() {
+        for dir in ["A", "B"] {
+            let dir = DECOMPRESS_SCRATCH.join(dir);
+            fs::create_dir_all(&dir).unwrap();
+            fs::copy(*TEST_DATA1, dir.join("big")).unwrap();
+        }
+        let groups = Scan::builder()
+            .root(*DECOMPRESS_SCRATCH)
+            .dirs(true)
+            .comparator(DecompressGroupComparator::new())
+            .run()
+            .unwrap()
+            .map(|group| group.unwrap())
+            .collect::<Vec<_>>();
+        fs::remove_dir_all(*DECOMPRESS_SCRATCH).unwrap();
+
+        // Without the exact comparator, the trees are still compared byte for byte.
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].comparator, "directory");
+    }
+
+    #[test]
+    fn non_recursive() {
+        for (dir, nested) in [("A", "one"), ("B", "two")] {
+            let dir = SHALLOW_SCRATCH.join(dir);
+            fs::create_dir_all(dir.join("nested")).unwrap();
+            fs::copy(*TEST_DATA1, dir.join("big")).unwrap();
+            fs::write(dir.join("nested").join("small"), nested).unwrap();
+        }
+        let groups = Scan::builder()
+            .roots(["A", "B"].map(|dir| SHALLOW_SCRATCH.join(dir)))
+            .dirs(true)
+            .non_recursive(true)
+            .run()
+            .unwrap()
+            .map(|group| group.unwrap())
+            .collect::<Vec<_>>();
+        fs::remove_dir_all(*SHALLOW_SCRATCH).unwrap();
+
+        // The nested directories were not walked, so neither root matches the other.
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].comparator, "exact");
+    }
+}
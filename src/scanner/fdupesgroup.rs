@@ -1,13 +1,20 @@
 use std::{
-    io::{self, BufRead, ErrorKind, Read},
+    collections::{BTreeMap, HashMap},
+    io::{BufRead, Read},
     path::{Path, PathBuf},
 };
 
 use crc::{crc16, Hasher16};
 use memcmp::Memcmp;
+use sha2::{Digest, Sha256};
 use tracing::debug;
 use crate::scanner::group_comparator::GroupComparator;
-use crate::DupeMessage;
+use crate::scanner::lockstep;
+use crate::error::{FdupesError, Result};
+use crate::manifest::Digests;
+use crate::progress::Progress;
+use crate::throttle::Throttle;
+use crate::DupeGroup;
 
 const BLOCK_SIZE: usize = 1024;
 
@@ -18,11 +25,50 @@ pub struct FdupesGroup<'a> {
     pub comparator: &'a dyn GroupComparator,
     partialcrc: Option<u16>,
     fullcrc: Option<u16>,
+    sha256: Option<String>,
+    fingerprint: Option<u64>,
+    /// Middle blocks sampled after the last block.
+    samples: usize,
+    /// CRCs of the sample stages read so far; `None` for a stage the
+    /// comparator cannot sample.
+    samplecrcs: Vec<Option<u16>>,
+    progress: Progress,
+    throttle: Throttle,
 }
 
 impl<'a> FdupesGroup<'a> {
-    pub fn into_dupe_message(self, total: usize, id: usize) -> DupeMessage {
-        (self.size, total, id, self.filenames)
+    pub fn into_dupe_group(self, total: usize, id: usize, roots: &[PathBuf]) -> DupeGroup {
+        let digest = self.digest();
+        DupeGroup::new(
+            id,
+            total,
+            self.size,
+            self.comparator.name(),
+            digest,
+            self.filenames,
+            roots,
+        )
+    }
+
+    /// As [`Self::into_dupe_group`], keeping this group.
+    pub fn to_dupe_group(&self, total: usize, id: usize, roots: &[PathBuf]) -> DupeGroup {
+        DupeGroup::new(
+            id,
+            total,
+            self.size,
+            self.comparator.name(),
+            self.digest(),
+            self.filenames.clone(),
+            roots,
+        )
+    }
+
+    fn digest(&self) -> Option<String> {
+        match (self.fingerprint, &self.sha256) {
+            (Some(fingerprint), _) => Some(format!("fingerprint:{fingerprint:016x}")),
+            (None, Some(sha256)) => Some(format!("sha256:{sha256}")),
+            (None, None) => None,
+        }
     }
 
     pub fn new(file: &Path, size: u64, comparator: &'a dyn GroupComparator) -> Self {
@@ -32,60 +78,129 @@ impl<'a> FdupesGroup<'a> {
             comparator,
             partialcrc: None,
             fullcrc: None,
+            sha256: None,
+            fingerprint: None,
+            samples: 0,
+            samplecrcs: Vec::new(),
+            progress: Progress::default(),
+            throttle: Throttle::default(),
         };
         n.add(file);
         n
     }
 
+    /// Count the bytes read while comparing towards `progress`.
+    pub(crate) fn with_progress(mut self, progress: Progress) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// Limit the rate of reads to `throttle`'s.
+    pub(crate) fn with_throttle(mut self, throttle: Throttle) -> Self {
+        self.throttle = throttle;
+        self
+    }
+
+    /// Compare `samples` evenly spaced blocks, after the first and last,
+    /// before reading files in full.
+    pub fn with_samples(mut self, samples: usize) -> Self {
+        self.samples = samples;
+        self
+    }
+
     pub fn add(&mut self, file: &Path) {
         self.filenames.push(file.to_owned());
     }
 
-    pub fn partialcrc(&mut self) -> io::Result<u16> {
+    /// Drop `file` from the group. Cached digests stay valid, as every
+    /// member shares the same content.
+    pub fn remove(&mut self, file: &Path) {
+        self.filenames.retain(|filename| filename != file);
+    }
+
+    pub fn partialcrc(&mut self) -> Result<u16> {
         if let Some(crc) = self.partialcrc {
             Ok(crc)
         } else {
-            let filename = self
-                .filenames
-                .get(0)
-                .ok_or_else(|| std::io::Error::new(ErrorKind::Other, "No files in group"))?;
-            let mut f = self.comparator.open(filename.to_str().unwrap())?;
-            //let mut f = File::open(filename)?;
+            let filename = self.filename()?;
+            let mut reader = self.open()?;
             let mut buffer = vec![0_u8; std::cmp::min(self.size, BLOCK_SIZE as u64) as usize];
 
-            f.reader.read_exact(&mut buffer[..])?;
+            reader
+                .read_exact(&mut buffer[..])
+                .map_err(|e| FdupesError::io(filename, e))?;
             let crc = crc16::checksum_usb(&buffer[..]);
             self.partialcrc = Some(crc);
             if self.size <= BLOCK_SIZE as u64 {
-                self.fullcrc = Some(crc);
+                self.fullcrc = Some(crc16::checksum_x25(&buffer[..]));
             }
             Ok(crc)
         }
     }
 
-    pub fn fullcrc(&mut self) -> io::Result<u16> {
+    /// Sample stages past the first block: the last block, then the middle
+    /// samples while they would not overlap.
+    pub fn stages(&self) -> usize {
+        let blocks = self.size / BLOCK_SIZE as u64;
+        if blocks < 2 {
+            0
+        } else {
+            1 + self.samples.min(blocks as usize - 2)
+        }
+    }
+
+    /// CRC of the block read at sample `stage`, caching each stage in turn.
+    pub fn samplecrc(&mut self, stage: usize) -> Result<Option<u16>> {
+        while self.samplecrcs.len() <= stage {
+            let offset = self.sample_offset(self.samplecrcs.len());
+            let filename = self.filename()?;
+            self.throttle.consume(BLOCK_SIZE as u64);
+            let block = self.comparator.sample(filename, offset, BLOCK_SIZE);
+            let read = match &block {
+                Ok(Some(block)) => block.len(),
+                _ => 0,
+            };
+            self.throttle.refund((BLOCK_SIZE - read) as u64);
+            let crc = block
+                .map_err(|e| FdupesError::io(filename, e))?
+                .map(|block| {
+                    self.progress.hashed(block.len() as u64);
+                    crc16::checksum_usb(&block)
+                });
+            self.samplecrcs.push(crc);
+        }
+        Ok(self.samplecrcs[stage])
+    }
+
+    fn sample_offset(&self, stage: usize) -> u64 {
+        let last = self.size - BLOCK_SIZE as u64;
+        if stage == 0 {
+            last
+        } else {
+            last * stage as u64 / (self.stages() as u64)
+        }
+    }
+
+    pub fn fullcrc(&mut self) -> Result<u16> {
         if let Some(crc) = self.fullcrc {
             Ok(crc)
         } else {
-            let filename = self
-                .filenames
-                .get(0)
-                .ok_or_else(|| std::io::Error::new(ErrorKind::Other, "No files in group"))?;
-            let mut f = self.comparator.open(filename.to_str().unwrap())?;
-            //let f = File::open(filename)?;
-            //let mut reader = BufReader::new(f);
+            let filename = self.filename()?;
+            let mut reader = self.open()?;
             let mut digest = crc16::Digest::new(crc16::X25);
 
             loop {
                 let length = {
-                    let buffer = f.reader.fill_buf()?;
+                    let buffer = reader
+                        .fill_buf()
+                        .map_err(|e| FdupesError::io(filename, e))?;
                     digest.write(buffer);
                     buffer.len()
                 };
                 if length == 0 {
                     break;
                 }
-                f.reader.consume(length);
+                reader.consume(length);
             }
 
             let crc = digest.sum16();
@@ -94,18 +209,204 @@ impl<'a> FdupesGroup<'a> {
         }
     }
 
-    fn open(&self) -> io::Result<Box<dyn BufRead>> {
-        let filename = match self.filenames.get(0) {
-            None => return Err(io::Error::new(io::ErrorKind::NotFound, "empty group")),
-            Some(name) => name,
-        };
-        let filename = match filename.to_str() {
-            None => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unrepresentable file: {:?}", filename))),
-            Some(name) => name,
+    pub fn fingerprint(&mut self) -> Result<Option<u64>> {
+        if self.fingerprint.is_none() {
+            let filename = self.filename()?;
+            self.fingerprint = self
+                .comparator
+                .fingerprint(filename)
+                .map_err(|e| FdupesError::io(filename, e))?;
+        }
+        Ok(self.fingerprint)
+    }
+
+    /// Digests recorded in a scan manifest: the partial and full CRCs and
+    /// the SHA-256, or the fingerprint alone for comparators grouping
+    /// similar content.
+    pub fn digests(&mut self) -> Result<Digests> {
+        match self.fingerprint()? {
+            Some(fingerprint) => Ok((None, None, None, Some(fingerprint))),
+            None => {
+                let partialcrc = self.partialcrc()?;
+                let sha256 = self.sha256()?;
+                Ok((Some(partialcrc), Some(self.fullcrc()?), Some(sha256), None))
+            }
+        }
+    }
+
+    /// The digests taken so far, as [`Self::digests`] orders them, without
+    /// reading anything.
+    pub(crate) fn known_digests(&self) -> Digests {
+        (
+            self.partialcrc,
+            self.fullcrc,
+            self.sha256.clone(),
+            self.fingerprint,
+        )
+    }
+
+    /// Take `digests`, recorded by an earlier scan, as those of this group's
+    /// content rather than reading its files for them.
+    pub(crate) fn with_digests(mut self, digests: Digests) -> Self {
+        let (partialcrc, fullcrc, sha256, fingerprint) = digests;
+        self.partialcrc = partialcrc;
+        self.fullcrc = fullcrc;
+        self.sha256 = sha256;
+        self.fingerprint = fingerprint;
+        self
+    }
+
+    /// SHA-256 of the whole content, in hex. The full CRC is taken in the
+    /// same read.
+    pub fn sha256(&mut self) -> Result<String> {
+        if let Some(sha256) = &self.sha256 {
+            return Ok(sha256.clone());
+        }
+        let filename = self.filename()?;
+        let mut reader = self.open()?;
+        let mut crc = crc16::Digest::new(crc16::X25);
+        let mut sha256 = Sha256::new();
+        loop {
+            let length = {
+                let buffer = reader
+                    .fill_buf()
+                    .map_err(|e| FdupesError::io(filename, e))?;
+                crc.write(buffer);
+                sha256.update(buffer);
+                buffer.len()
+            };
+            if length == 0 {
+                break;
+            }
+            reader.consume(length);
+        }
+        let sha256 = format!("{:x}", sha256.finalize());
+        self.fullcrc = Some(crc.sum16());
+        self.sha256 = Some(sha256.clone());
+        Ok(sha256)
+    }
+
+    fn filename(&self) -> Result<&Path> {
+        self.filenames
+            .first()
+            .map(PathBuf::as_path)
+            .ok_or(FdupesError::EmptyGroup)
+    }
+
+    /// Split this group of candidates into groups of byte-identical files,
+    /// reading them in lockstep with at most `max_open` open at once. Groups
+    /// matched by fingerprint hold similar rather than identical files, so
+    /// are kept whole.
+    pub fn split(self, max_open: usize) -> Vec<Self> {
+        if self.fingerprint.is_some() || self.filenames.len() < 2 {
+            return vec![self];
+        }
+        let max_open = max_open.max(2);
+        let sets = if self.filenames.len() <= max_open {
+            self.identical(&self.filenames, false)
+        } else {
+            self.identical_batched(max_open)
         };
-        self.comparator.open(filename)
-        .map(|v| {v.reader})
-        //self.comparator.open(self.filenames.get(0).unwrap().to_str().unwrap()).unwrap().reader
+        sets.into_iter()
+            .map(|(filenames, crc, sha256)| Self {
+                filenames,
+                size: self.size,
+                comparator: self.comparator,
+                partialcrc: self.partialcrc,
+                fullcrc: Some(crc),
+                sha256: Some(sha256),
+                fingerprint: None,
+                samples: self.samples,
+                samplecrcs: self.samplecrcs.clone(),
+                progress: self.progress.clone(),
+                throttle: self.throttle.clone(),
+            })
+            .collect()
+    }
+
+    /// Too many files to open at once: partition them by digest, reading
+    /// one at a time, then compare each partition in batches, merging each
+    /// batch's sets into any earlier set of the same content.
+    fn identical_batched(&self, max_open: usize) -> Vec<(Vec<PathBuf>, u16, String)> {
+        let mut by_digest: BTreeMap<(u16, String), Vec<PathBuf>> = BTreeMap::new();
+        for filename in &self.filenames {
+            let mut file = Self::new(filename, self.size, self.comparator)
+                .with_progress(self.progress.clone())
+                .with_throttle(self.throttle.clone());
+            // Taking the SHA-256 takes the full CRC too.
+            match file.sha256().and_then(|sha256| Ok((file.fullcrc()?, sha256))) {
+                Ok(digest) => by_digest.entry(digest).or_default().push(filename.clone()),
+                Err(e) => debug!(error = debug(e), "sha256"),
+            }
+        }
+
+        let mut sets = Vec::new();
+        for ((crc, sha256), candidates) in by_digest.into_iter().filter(|(_, f)| f.len() > 1) {
+            let mut merged: Vec<Vec<PathBuf>> = Vec::new();
+            for batch in candidates.chunks(max_open) {
+                for (members, _, _) in self.identical(batch, true) {
+                    let same = merged.iter_mut().find(|set| {
+                        self.identical(&[set[0].clone(), members[0].clone()], false)
+                            .len()
+                            == 1
+                    });
+                    match same {
+                        Some(set) => set.extend(members),
+                        None => merged.push(members),
+                    }
+                }
+            }
+            sets.extend(
+                merged
+                    .into_iter()
+                    .filter(|set| set.len() > 1)
+                    .map(|set| (set, crc, sha256.clone())),
+            );
+        }
+        let position = self
+            .filenames
+            .iter()
+            .enumerate()
+            .map(|(position, filename)| (filename, position))
+            .collect::<HashMap<_, _>>();
+        sets.sort_by_key(|(set, _, _)| position.get(&set[0]).copied());
+        sets
+    }
+
+    fn identical(
+        &self,
+        filenames: &[PathBuf],
+        keep_singletons: bool,
+    ) -> Vec<(Vec<PathBuf>, u16, String)> {
+        let readers = filenames
+            .iter()
+            .enumerate()
+            .filter_map(|(index, filename)| match self.open_path(filename) {
+                Ok(reader) => Some((index, reader)),
+                Err(e) => {
+                    debug!(error = debug(e), "open");
+                    None
+                }
+            })
+            .collect();
+        lockstep::identical(readers, keep_singletons)
+            .into_iter()
+            .map(|(indices, crc, sha256)| {
+                let set = indices.into_iter().map(|index| filenames[index].clone());
+                (set.collect(), crc, sha256)
+            })
+            .collect()
+    }
+
+    fn open(&self) -> Result<Box<dyn BufRead>> {
+        self.open_path(self.filename()?)
+    }
+
+    fn open_path(&self, filename: &Path) -> Result<Box<dyn BufRead>> {
+        self.comparator
+            .open(filename)
+            .map(|v| self.throttle.wrap(self.progress.wrap(v.reader)))
+            .map_err(|e| FdupesError::io(filename, e))
     }
 }
 
@@ -212,6 +513,83 @@ mod tests {
         assert_ne!(group1.fullcrc().unwrap(), group2.fullcrc().unwrap());
     }
 
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_names() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let file_a = Path::new(OsStr::from_bytes(b"test_data/non_utf8_scratch_\xe9_a"));
+        let file_b = Path::new(OsStr::from_bytes(b"test_data/non_utf8_scratch_\xe9_b"));
+        fs::copy(*TEST_DATA1, file_a).unwrap();
+        fs::copy(*TEST_DATA1, file_b).unwrap();
+
+        let mut group_a = test_group(&[file_a]);
+        let mut group_b = test_group(&[file_b]);
+        assert_eq!(group_a.fullcrc().unwrap(), group_b.fullcrc().unwrap());
+        assert_eq!(group_a, group_b);
+
+        fs::remove_file(file_a).unwrap();
+        fs::remove_file(file_b).unwrap();
+    }
+
+    #[test]
+    fn split_batches() {
+        let files = ["a", "b", "c", "d", "e"]
+            .map(|name| format!("test_data/split_scratch_{name}.txt"));
+        for (file, trail) in files.iter().zip([1, 2, 1, 1, 2]) {
+            generate_test_file(&TEST_DATA1, Path::new(file), trail);
+        }
+        let paths = files.iter().map(Path::new).collect::<Vec<_>>();
+
+        for max_open in [2, 256] {
+            let groups = test_group(&paths)
+                .split(max_open)
+                .into_iter()
+                .map(|group| group.filenames)
+                .collect::<Vec<_>>();
+            assert_eq!(
+                groups,
+                vec![
+                    vec![paths[0], paths[2], paths[3]],
+                    vec![paths[1], paths[4]]
+                ],
+                "max_open {max_open}"
+            );
+        }
+
+        for file in &files {
+            fs::remove_file(file).unwrap();
+        }
+    }
+
+    #[test]
+    fn samples() {
+        let files = ["a", "b", "c"].map(|name| format!("test_data/samples_scratch_{name}.bin"));
+        let content = vec![0_u8; 10 * 1024];
+        let mut tail = content.clone();
+        tail[10 * 1024 - 1] = 1;
+        let mut middle = content.clone();
+        middle[5 * 1024] = 1;
+        for (file, content) in files.iter().zip([&content, &tail, &middle]) {
+            fs::write(file, content).unwrap();
+        }
+        let group = |file: &String| test_group(&[Path::new(file)]).with_samples(3);
+        let (mut a, mut b, mut c) = (group(&files[0]), group(&files[1]), group(&files[2]));
+
+        assert_eq!(a.stages(), 4);
+        assert_eq!(a.partialcrc().unwrap(), b.partialcrc().unwrap());
+        assert_ne!(a.samplecrc(0).unwrap(), b.samplecrc(0).unwrap());
+        assert_eq!(a.samplecrc(0).unwrap(), c.samplecrc(0).unwrap());
+        assert!((1..a.stages())
+            .any(|stage| a.samplecrc(stage).unwrap() != c.samplecrc(stage).unwrap()));
+        assert_eq!(test_group(&[&TEST_DATA1]).with_samples(3).stages(), 0);
+
+        for file in &files {
+            fs::remove_file(file).unwrap();
+        }
+    }
+
     fn generate_test_file(source: &Path, target: &Path, trail: u64) {
         fs::copy(source, target).unwrap();
         let mut file = fs::OpenOptions::new()
@@ -0,0 +1,276 @@
+use std::{
+    io::{self, Read},
+    path::Path,
+};
+
+use crate::{archive, Throttle};
+
+const ROLLING_WINDOW: usize = 7;
+const MIN_BLOCKSIZE: u32 = 3;
+const SPAMSUM_LENGTH: usize = 64;
+const HASH_PRIME: u32 = 0x0100_0193;
+const HASH_INIT: u32 = 0x2802_1967;
+const B64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Context-triggered piecewise hash, in the style of ssdeep's spamsum.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FuzzyHash {
+    block_size: u32,
+    first: Vec<u8>,
+    second: Vec<u8>,
+}
+
+#[derive(Default)]
+struct RollingHash {
+    window: [u8; ROLLING_WINDOW],
+    h1: u32,
+    h2: u32,
+    h3: u32,
+    n: usize,
+}
+
+impl RollingHash {
+    fn update(&mut self, c: u8) -> u32 {
+        let c32 = u32::from(c);
+        self.h2 = self.h2.wrapping_sub(self.h1);
+        self.h2 = self
+            .h2
+            .wrapping_add((ROLLING_WINDOW as u32).wrapping_mul(c32));
+        self.h1 = self.h1.wrapping_add(c32);
+        self.h1 = self
+            .h1
+            .wrapping_sub(u32::from(self.window[self.n % ROLLING_WINDOW]));
+        self.window[self.n % ROLLING_WINDOW] = c;
+        self.n += 1;
+        self.h3 = (self.h3 << 5) ^ c32;
+        self.h1.wrapping_add(self.h2).wrapping_add(self.h3)
+    }
+}
+
+fn sum_hash(c: u8, h: u32) -> u32 {
+    h.wrapping_mul(HASH_PRIME) ^ u32::from(c)
+}
+
+impl FuzzyHash {
+    /// Hash the file at `path`, whose content is `size` bytes long, reading
+    /// no faster than `throttle` allows.
+    pub fn from_path(path: &Path, size: u64, throttle: &Throttle) -> io::Result<Self> {
+        let mut block_size = MIN_BLOCKSIZE;
+        while u64::from(block_size) * (SPAMSUM_LENGTH as u64) < size {
+            block_size *= 2;
+        }
+        loop {
+            let hash = Self::digest(throttle.wrap(archive::open(path)?), block_size)?;
+            // Too few trigger points for a meaningful signature: retry smaller blocks.
+            if block_size > MIN_BLOCKSIZE && hash.first.len() < SPAMSUM_LENGTH / 2 {
+                block_size /= 2;
+            } else {
+                return Ok(hash);
+            }
+        }
+    }
+
+    fn digest(reader: impl Read, block_size: u32) -> io::Result<Self> {
+        let mut rolling = RollingHash::default();
+        let (mut h1, mut h2) = (HASH_INIT, HASH_INIT);
+        let (mut first, mut second) = (Vec::new(), Vec::new());
+        let mut last = 0;
+        for c in io::BufReader::new(reader).bytes() {
+            let c = c?;
+            last = rolling.update(c);
+            h1 = sum_hash(c, h1);
+            h2 = sum_hash(c, h2);
+            if last % block_size == block_size - 1 && first.len() < SPAMSUM_LENGTH - 1 {
+                first.push(B64[(h1 % 64) as usize]);
+                h1 = HASH_INIT;
+            }
+            if last % (block_size * 2) == block_size * 2 - 1
+                && second.len() < SPAMSUM_LENGTH / 2 - 1
+            {
+                second.push(B64[(h2 % 64) as usize]);
+                h2 = HASH_INIT;
+            }
+        }
+        if last != 0 {
+            first.push(B64[(h1 % 64) as usize]);
+            second.push(B64[(h2 % 64) as usize]);
+        }
+        Ok(Self {
+            block_size,
+            first,
+            second,
+        })
+    }
+
+    pub fn block_size(&self) -> u32 {
+        self.block_size
+    }
+
+    /// Similarity score between 0 (unrelated) and 100 (identical signatures).
+    pub fn compare(&self, other: &Self) -> u32 {
+        if self == other {
+            return 100;
+        }
+        self.comparable(other)
+            .into_iter()
+            .map(|(a, b, block_size)| score(a, b, block_size))
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Upper bound of `compare`, from the lengths of the signatures alone:
+    /// their edit distance is at least the difference in length.
+    pub fn max_score(&self, other: &Self) -> u32 {
+        if self == other {
+            return 100;
+        }
+        self.comparable(other)
+            .into_iter()
+            .map(|(a, b, _)| max_score(a, b))
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Pairs of signatures with a common block size, and that block size.
+    /// Hashes whose block sizes differ by more than a factor of two have none.
+    fn comparable<'a>(&'a self, other: &'a Self) -> Vec<(&'a [u8], &'a [u8], u32)> {
+        let (a, b) = (self.block_size, other.block_size);
+        if a == b {
+            vec![
+                (&self.first, &other.first, a),
+                (&self.second, &other.second, a * 2),
+            ]
+        } else if a == b * 2 {
+            vec![(&self.first, &other.second, a)]
+        } else if b == a * 2 {
+            vec![(&self.second, &other.first, b)]
+        } else {
+            vec![]
+        }
+    }
+}
+
+/// Collapse runs of more than three identical characters.
+fn eliminate_sequences(s: &[u8]) -> Vec<u8> {
+    let mut result: Vec<u8> = Vec::with_capacity(s.len());
+    for (i, c) in s.iter().enumerate() {
+        if i < 3 || s[i - 1] != *c || s[i - 2] != *c || s[i - 3] != *c {
+            result.push(*c);
+        }
+    }
+    result
+}
+
+fn has_common_substring(a: &[u8], b: &[u8]) -> bool {
+    a.windows(ROLLING_WINDOW)
+        .any(|window| b.windows(ROLLING_WINDOW).any(|other| window == other))
+}
+
+// Edit distance counting insertions and deletions only (a change costs 2).
+fn edit_distance(a: &[u8], b: &[u8]) -> usize {
+    let mut previous = (0..=b.len()).collect::<Vec<_>>();
+    let mut current = vec![0; b.len() + 1];
+    for (i, ca) in a.iter().enumerate() {
+        current[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let change = if ca == cb { 0 } else { 2 };
+            current[j + 1] = (previous[j] + change)
+                .min(previous[j + 1] + 1)
+                .min(current[j] + 1);
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+    previous[b.len()]
+}
+
+fn score(a: &[u8], b: &[u8], block_size: u32) -> u32 {
+    let (a, b) = (eliminate_sequences(a), eliminate_sequences(b));
+    if a.len() < ROLLING_WINDOW || b.len() < ROLLING_WINDOW || !has_common_substring(&a, &b) {
+        return 0;
+    }
+    let Some(score) = distance_score(edit_distance(&a, &b), a.len() + b.len()) else {
+        return 0;
+    };
+    // Small block sizes make short signatures look more alike than they are.
+    let cap = block_size / MIN_BLOCKSIZE * a.len().min(b.len()) as u32;
+    score.min(cap)
+}
+
+/// Score of signatures `len` long between them, `distance` edits apart.
+fn distance_score(distance: usize, len: usize) -> Option<u32> {
+    let distance = (distance * SPAMSUM_LENGTH / len) as u32;
+    let distance = 100 * distance / SPAMSUM_LENGTH as u32;
+    (distance < 100).then(|| 100 - distance)
+}
+
+fn max_score(a: &[u8], b: &[u8]) -> u32 {
+    let (a, b) = (eliminate_sequences(a), eliminate_sequences(b));
+    if a.len() < ROLLING_WINDOW || b.len() < ROLLING_WINDOW {
+        return 0;
+    }
+    distance_score(a.len().abs_diff(b.len()), a.len() + b.len()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FuzzyHash;
+    use crate::Throttle;
+    use std::fs;
+    use std::path::Path;
+
+    lazy_static::lazy_static! {
+    static ref ORIGINAL_FILENAME: &'static Path = Path::new("test_data/fuzzyhash_scratch_original.txt");
+    static ref EDITED_FILENAME: &'static Path = Path::new("test_data/fuzzyhash_scratch_edited.txt");
+    static ref IDENTICAL_FILENAME: &'static Path = Path::new("test_data/fuzzyhash_scratch_identical.txt");
+    }
+
+    fn text(seed: u64, lines: usize) -> String {
+        let mut state = seed;
+        (0..lines)
+            .map(|line| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                format!(
+                    "clause {line}: party {} agrees to term {}\n",
+                    state >> 40,
+                    state >> 52
+                )
+            })
+            .collect()
+    }
+
+    fn hash(path: &Path, content: &str) -> FuzzyHash {
+        fs::write(path, content).unwrap();
+        let hash = FuzzyHash::from_path(path, content.len() as u64, &Throttle::default()).unwrap();
+        fs::remove_file(path).unwrap();
+        hash
+    }
+
+    #[test]
+    fn identical() {
+        let original = hash(&IDENTICAL_FILENAME, &text(1, 400));
+        assert_eq!(original.compare(&original), 100);
+    }
+
+    #[test]
+    fn edited_copy() {
+        let content = text(2, 400);
+        let edited = content.replacen("clause 200", "clause two hundred", 1);
+        let original = hash(&ORIGINAL_FILENAME, &content);
+        let edited = hash(&EDITED_FILENAME, &edited);
+        let other = hash(&EDITED_FILENAME, &text(3, 400));
+
+        assert!(
+            original.compare(&edited) >= 80,
+            "{}",
+            original.compare(&edited)
+        );
+        assert!(original.max_score(&edited) >= original.compare(&edited));
+        assert!(
+            original.compare(&other) < 20,
+            "{}",
+            original.compare(&other)
+        );
+    }
+}
@@ -0,0 +1,171 @@
+use std::{
+    io::{self, BufRead, BufReader},
+    path::Path,
+};
+
+use bzip2::read::BzDecoder;
+use flate2::read::MultiGzDecoder;
+use tracing::debug;
+use xz2::read::XzDecoder;
+
+use super::{GroupComparator, GroupReader};
+use crate::{archive, IoStrategy, Throttle};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Compression {
+    None,
+    Gzip,
+    Xz,
+    Zstd,
+    Bzip2,
+}
+
+impl Compression {
+    fn detect(header: &[u8]) -> Self {
+        if header.starts_with(&[0x1f, 0x8b]) {
+            Self::Gzip
+        } else if header.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Self::Xz
+        } else if header.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Self::Zstd
+        } else if header.starts_with(b"BZh") {
+            Self::Bzip2
+        } else {
+            Self::None
+        }
+    }
+}
+
+/// Compares the decompressed payload of gzip, xz, zstd and bzip2 files.
+/// Uncompressed files are passed through untouched, so `report.csv` and
+/// `report.csv.gz` end up in the same bucket when their contents match.
+#[derive(Debug)]
+pub struct DecompressGroupComparator {
+    io: IoStrategy,
+    throttle: Throttle,
+}
+impl Default for DecompressGroupComparator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl GroupComparator for DecompressGroupComparator {
+    fn name(&self) -> &str {
+        "decompress"
+    }
+
+    fn can_analyse(&self, path: &Path) -> bool {
+        let can_analyse = true;
+        debug!(path = debug(path), can_analyse, "can_analyse");
+        can_analyse
+    }
+
+    fn size(&self, path: &Path, raw_size: u64) -> io::Result<u64> {
+        let (compression, reader) = self.open_raw(path)?;
+        if compression == Compression::None {
+            return Ok(raw_size);
+        }
+        let mut reader = Self::decoder(compression, self.throttle.wrap(reader))?;
+        io::copy(&mut reader, &mut io::sink())
+    }
+
+    fn open(&self, path: &Path) -> io::Result<GroupReader> {
+        let (compression, reader) = self.open_raw(path)?;
+        Self::decoder(compression, reader).map(|reader| GroupReader { reader })
+    }
+
+    fn set_throttle(&mut self, throttle: Throttle) {
+        self.throttle = throttle;
+    }
+}
+
+impl DecompressGroupComparator {
+    pub fn new() -> Self {
+        Self {
+            io: IoStrategy::default(),
+            throttle: Throttle::default(),
+        }
+    }
+
+    /// Read files with `io` rather than the default strategy.
+    pub fn with_io(mut self, io: IoStrategy) -> Self {
+        self.io = io;
+        self
+    }
+
+    fn open_raw(&self, path: &Path) -> io::Result<(Compression, Box<dyn BufRead>)> {
+        let mut reader = archive::open_with(path, self.io)?;
+        let compression = Compression::detect(reader.fill_buf()?);
+        debug!(path = debug(path), compression = debug(compression), "detect");
+        Ok((compression, reader))
+    }
+
+    fn decoder(compression: Compression, reader: Box<dyn BufRead>) -> io::Result<Box<dyn BufRead>> {
+        Ok(match compression {
+            Compression::None => reader,
+            Compression::Gzip => Box::new(BufReader::new(MultiGzDecoder::new(reader))),
+            Compression::Xz => Box::new(BufReader::new(XzDecoder::new_multi_decoder(reader))),
+            Compression::Zstd => Box::new(BufReader::new(zstd::Decoder::with_buffer(reader)?)),
+            Compression::Bzip2 => Box::new(BufReader::new(BzDecoder::new(reader))),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DecompressGroupComparator;
+    use crate::scanner::group_comparator::GroupComparator;
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::path::Path;
+
+    lazy_static::lazy_static! {
+    static ref TEST_DATA1: &'static Path = Path::new("test_data/file1.txt");
+    static ref GZIP_FILENAME: &'static Path = Path::new("test_data/decompress_scratch.txt.gz");
+    static ref ZSTD_FILENAME: &'static Path = Path::new("test_data/decompress_scratch.txt.zst");
+    }
+
+    fn payload(comparator: &DecompressGroupComparator, path: &Path) -> Vec<u8> {
+        let mut content = Vec::new();
+        comparator
+            .open(path)
+            .unwrap()
+            .reader
+            .read_to_end(&mut content)
+            .unwrap();
+        content
+    }
+
+    #[test]
+    fn passthrough() {
+        let comparator = DecompressGroupComparator::new();
+        let raw_size = TEST_DATA1.metadata().unwrap().len();
+
+        assert_eq!(comparator.size(&TEST_DATA1, raw_size).unwrap(), raw_size);
+        assert_eq!(payload(&comparator, &TEST_DATA1), fs::read(*TEST_DATA1).unwrap());
+    }
+
+    #[test]
+    fn compressed() {
+        let comparator = DecompressGroupComparator::new();
+        let content = fs::read(*TEST_DATA1).unwrap();
+
+        let mut encoder = flate2::write::GzEncoder::new(
+            fs::File::create(*GZIP_FILENAME).unwrap(),
+            flate2::Compression::default(),
+        );
+        encoder.write_all(&content).unwrap();
+        encoder.finish().unwrap();
+        fs::write(*ZSTD_FILENAME, zstd::encode_all(&content[..], 0).unwrap()).unwrap();
+
+        for path in [*GZIP_FILENAME, *ZSTD_FILENAME] {
+            let raw_size = path.metadata().unwrap().len();
+            assert_ne!(raw_size, content.len() as u64);
+            assert_eq!(comparator.size(path, raw_size).unwrap(), content.len() as u64);
+            assert_eq!(payload(&comparator, path), content);
+        }
+
+        fs::remove_file(*GZIP_FILENAME).unwrap();
+        fs::remove_file(*ZSTD_FILENAME).unwrap();
+    }
+}
@@ -1,11 +1,14 @@
-use std::{path::Path, io::{self, BufReader}, fs::File};
+use std::{fs::File, io::{self, Read, Seek, SeekFrom}, path::Path};
 
 use tracing::debug;
 
 use super::{GroupComparator,GroupReader};
+use crate::{archive, IoStrategy};
 
 #[derive(Debug)]
-pub struct ExactGroupComparator {}
+pub struct ExactGroupComparator {
+    io: IoStrategy,
+}
 impl Default for ExactGroupComparator {
     fn default() -> Self {
         Self::new()
@@ -22,14 +25,35 @@ impl GroupComparator for ExactGroupComparator {
         can_analyse
     }
 
-    fn open(&self, path: &str) -> io::Result<GroupReader> {
-        File::open(path).map(|f| GroupReader {
-            reader: Box::new(BufReader::new(f)),
-        })
+    fn byte_exact(&self) -> bool {
+        true
+    }
+
+    fn open(&self, path: &Path) -> io::Result<GroupReader> {
+        archive::open_with(path, self.io).map(|reader| GroupReader { reader })
+    }
+
+    fn sample(&self, path: &Path, offset: u64, len: usize) -> io::Result<Option<Vec<u8>>> {
+        if archive::is_member(path) {
+            return Ok(None);
+        }
+        let mut file = File::open(path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut buffer = vec![0_u8; len];
+        file.read_exact(&mut buffer)?;
+        Ok(Some(buffer))
     }
 }
 impl ExactGroupComparator {
     pub fn new() -> Self {
-        Self {}
+        Self {
+            io: IoStrategy::default(),
+        }
+    }
+
+    /// Read files with `io` rather than the default strategy.
+    pub fn with_io(mut self, io: IoStrategy) -> Self {
+        self.io = io;
+        self
     }
 }
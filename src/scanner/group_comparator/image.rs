@@ -0,0 +1,179 @@
+use std::{
+    io::{self, Cursor, Read},
+    path::Path,
+};
+
+use image::imageops::FilterType;
+use tracing::debug;
+
+use super::{GroupComparator, GroupReader};
+use crate::{archive, Throttle};
+
+const EXTENSIONS: [&str; 5] = ["jpg", "jpeg", "png", "webp", "gif"];
+const DEFAULT_MAX_DISTANCE: u32 = 8;
+const ASPECT_STEPS: u64 = 32;
+/// Leading bytes read for an image's dimensions; only if those lie further
+/// in is the rest read too.
+const HEADER_LEN: u64 = 256 * 1024;
+
+/// Groups images whose perceptual hash (dHash) differs by at most
+/// `max_distance` bits, catching resized and re-encoded copies.
+#[derive(Debug)]
+pub struct ImageGroupComparator {
+    max_distance: u32,
+    throttle: Throttle,
+}
+impl Default for ImageGroupComparator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl GroupComparator for ImageGroupComparator {
+    fn name(&self) -> &str {
+        "image"
+    }
+
+    fn can_analyse(&self, path: &Path) -> bool {
+        let can_analyse = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_ascii_lowercase())
+            .is_some_and(|ext| EXTENSIONS.contains(&ext.as_str()));
+        debug!(path = debug(path), can_analyse, "can_analyse");
+        can_analyse
+    }
+
+    // Copies differ in size, but resizing keeps the aspect ratio: bucket by
+    // that, in steps of 1/32. Ratios at a step's edge may fall either side.
+    fn bucket(&self, path: &Path, _size: u64) -> io::Result<u64> {
+        let (width, height) = self.dimensions(path)?;
+        Ok((u64::from(width) * ASPECT_STEPS + u64::from(height) / 2) / u64::from(height.max(1)))
+    }
+
+    fn fingerprint(&self, path: &Path) -> io::Result<Option<u64>> {
+        self.dhash(path).map(Some)
+    }
+
+    fn max_distance(&self) -> u32 {
+        self.max_distance
+    }
+
+    fn open(&self, path: &Path) -> io::Result<GroupReader> {
+        archive::open(path).map(|reader| GroupReader { reader })
+    }
+
+    fn set_throttle(&mut self, throttle: Throttle) {
+        self.throttle = throttle;
+    }
+}
+
+impl ImageGroupComparator {
+    pub fn new() -> Self {
+        Self::with_max_distance(DEFAULT_MAX_DISTANCE)
+    }
+
+    pub fn with_max_distance(max_distance: u32) -> Self {
+        Self {
+            max_distance,
+            throttle: Throttle::default(),
+        }
+    }
+
+    fn dimensions(&self, path: &Path) -> io::Result<(u32, u32)> {
+        let mut reader = self.throttle.wrap(archive::open(path)?);
+        let mut content = Vec::new();
+        (&mut reader).take(HEADER_LEN).read_to_end(&mut content)?;
+        if let Ok(dimensions) = Self::decode_dimensions(&content) {
+            return Ok(dimensions);
+        }
+        reader.read_to_end(&mut content)?;
+        Self::decode_dimensions(&content).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn decode_dimensions(content: &[u8]) -> image::ImageResult<(u32, u32)> {
+        image::ImageReader::new(Cursor::new(content))
+            .with_guessed_format()?
+            .into_dimensions()
+    }
+
+    /// Difference hash: shrink to 9x8 greyscale, then record whether each
+    /// pixel is brighter than its right-hand neighbour.
+    fn dhash(&self, path: &Path) -> io::Result<u64> {
+        let mut content = Vec::new();
+        self.throttle
+            .wrap(archive::open(path)?)
+            .read_to_end(&mut content)?;
+        let image = image::load_from_memory(&content)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+            .resize_exact(9, 8, FilterType::Triangle)
+            .to_luma8();
+        let mut hash = 0_u64;
+        for y in 0..8 {
+            for x in 0..8 {
+                let left = image.get_pixel(x, y)[0];
+                let right = image.get_pixel(x + 1, y)[0];
+                hash = (hash << 1) | u64::from(left > right);
+            }
+        }
+        debug!(path = debug(path), hash, "dhash");
+        Ok(hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ImageGroupComparator;
+    use crate::scanner::group_comparator::GroupComparator;
+    use image::{imageops::FilterType, DynamicImage, RgbImage};
+    use std::fs;
+    use std::path::Path;
+
+    lazy_static::lazy_static! {
+    static ref ORIGINAL_FILENAME: &'static Path = Path::new("test_data/image_scratch_original.png");
+    static ref RESIZED_FILENAME: &'static Path = Path::new("test_data/image_scratch_resized.jpg");
+    static ref OTHER_FILENAME: &'static Path = Path::new("test_data/image_scratch_other.png");
+    }
+
+    fn distance(comparator: &ImageGroupComparator, a: &Path, b: &Path) -> u32 {
+        let a = comparator.fingerprint(a).unwrap().unwrap();
+        let b = comparator.fingerprint(b).unwrap().unwrap();
+        (a ^ b).count_ones()
+    }
+
+    #[test]
+    fn resized_copy() {
+        let comparator = ImageGroupComparator::new();
+        let original = DynamicImage::ImageRgb8(RgbImage::from_fn(128, 96, |x, y| {
+            image::Rgb([(x * 2) as u8, (y * 2) as u8, ((x + y) % 256) as u8])
+        }));
+        let other = DynamicImage::ImageRgb8(RgbImage::from_fn(128, 96, |x, y| {
+            let v = if (x / 16 + y / 16) % 2 == 0 { 255 } else { 0 };
+            image::Rgb([v, v, v])
+        }));
+        original.save(*ORIGINAL_FILENAME).unwrap();
+        original
+            .resize_exact(64, 48, FilterType::Triangle)
+            .save(*RESIZED_FILENAME)
+            .unwrap();
+        other.save(*OTHER_FILENAME).unwrap();
+
+        assert!(comparator.can_analyse(&ORIGINAL_FILENAME));
+        assert!(!comparator.can_analyse(Path::new("test_data/file1.txt")));
+        assert!(
+            distance(&comparator, &ORIGINAL_FILENAME, &RESIZED_FILENAME)
+                <= comparator.max_distance()
+        );
+        assert!(
+            distance(&comparator, &ORIGINAL_FILENAME, &OTHER_FILENAME) > comparator.max_distance()
+        );
+
+        assert_eq!(
+            comparator.bucket(&ORIGINAL_FILENAME, 0).unwrap(),
+            comparator.bucket(&RESIZED_FILENAME, 0).unwrap()
+        );
+
+        fs::remove_file(*ORIGINAL_FILENAME).unwrap();
+        fs::remove_file(*RESIZED_FILENAME).unwrap();
+        fs::remove_file(*OTHER_FILENAME).unwrap();
+    }
+}
@@ -28,8 +28,13 @@ impl GroupComparator for JsonGroupComparator {
         // can_analyse
     }
 
-    fn open(&self, path: &str) -> io::Result<GroupReader> {
-        File::open(path).and_then(|_| Err(io::Error::new(io::ErrorKind::Unsupported, path)))
+    fn open(&self, path: &Path) -> io::Result<GroupReader> {
+        File::open(path).and_then(|_| {
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                path.display().to_string(),
+            ))
+        })
     }
 }
 
@@ -0,0 +1,446 @@
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, Cursor, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use tracing::debug;
+
+use super::{GroupComparator, GroupReader};
+use crate::{archive, Throttle};
+
+const ID3V1_LEN: u64 = 128;
+/// Bytes read at a time by `WithoutId3v1`.
+const CHUNK_LEN: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MediaKind {
+    Jpeg,
+    Mp3,
+    Flac,
+}
+
+impl MediaKind {
+    fn detect(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()?.to_ascii_lowercase().as_str() {
+            "jpg" | "jpeg" => Some(Self::Jpeg),
+            "mp3" => Some(Self::Mp3),
+            "flac" => Some(Self::Flac),
+            _ => None,
+        }
+    }
+}
+
+/// Compares the payload of JPEG, MP3 and FLAC files, skipping the metadata
+/// (EXIF and other APP segments, ID3 tags, FLAC metadata blocks) so files
+/// differing only in their tags are grouped together.
+#[derive(Debug)]
+pub struct MediaGroupComparator {
+    throttle: Throttle,
+}
+impl Default for MediaGroupComparator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl GroupComparator for MediaGroupComparator {
+    fn name(&self) -> &str {
+        "media"
+    }
+
+    fn can_analyse(&self, path: &Path) -> bool {
+        let can_analyse = MediaKind::detect(path).is_some();
+        debug!(path = debug(path), can_analyse, "can_analyse");
+        can_analyse
+    }
+
+    fn size(&self, path: &Path, raw_size: u64) -> io::Result<u64> {
+        let kind = Self::kind(path)?;
+        let reader = self.throttle.wrap(archive::open(path)?);
+        let (header, rest) = Header::parse(kind, reader, Throttle::default())?;
+        let size = raw_size.saturating_sub(header.skipped);
+        if kind == MediaKind::Mp3 && Self::ends_with_id3v1(path, header.kept.chain(rest), size)? {
+            return Ok(size - ID3V1_LEN);
+        }
+        Ok(size)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<GroupReader> {
+        // The payload is throttled by the scanner as it is consumed, but the
+        // header is paid for as it is parsed here.
+        let reader = archive::open(path)?;
+        Self::payload(Self::kind(path)?, reader, self.throttle.clone())
+            .map(|reader| GroupReader { reader })
+    }
+
+    fn set_throttle(&mut self, throttle: Throttle) {
+        self.throttle = throttle;
+    }
+}
+
+impl MediaGroupComparator {
+    pub fn new() -> Self {
+        Self {
+            throttle: Throttle::default(),
+        }
+    }
+
+    fn kind(path: &Path) -> io::Result<MediaKind> {
+        MediaKind::detect(path)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Unsupported, "not a media file"))
+    }
+
+    fn payload(
+        kind: MediaKind,
+        reader: Box<dyn BufRead>,
+        throttle: Throttle,
+    ) -> io::Result<Box<dyn BufRead>> {
+        let (header, rest) = Header::parse(kind, reader, throttle)?;
+        let payload = header.kept.chain(rest);
+        Ok(match kind {
+            MediaKind::Mp3 => Box::new(BufReader::new(WithoutId3v1::new(payload))),
+            _ => Box::new(payload),
+        })
+    }
+
+    /// Whether the `len` bytes of `payload` end with an ID3v1 tag. Files are
+    /// checked by seeking to their end; archive members are read through.
+    fn ends_with_id3v1(path: &Path, mut payload: impl Read, len: u64) -> io::Result<bool> {
+        if len < ID3V1_LEN {
+            return Ok(false);
+        }
+        let mut tag = [0; 3];
+        if archive::is_member(path) {
+            io::copy(&mut (&mut payload).take(len - ID3V1_LEN), &mut io::sink())?;
+            payload.read_exact(&mut tag)?;
+        } else {
+            let mut file = File::open(path)?;
+            file.seek(SeekFrom::End(-(ID3V1_LEN as i64)))?;
+            file.read_exact(&mut tag)?;
+        }
+        Ok(&tag == b"TAG")
+    }
+}
+
+/// The leading metadata of a media file, read no further than needed.
+struct Header {
+    /// Content read while parsing which belongs to the payload.
+    kept: Cursor<Vec<u8>>,
+    /// Metadata bytes dropped from the payload.
+    skipped: u64,
+}
+
+/// Parses a header from a reader, keeping bytes read but not yet classified.
+struct Parser {
+    reader: Box<dyn BufRead>,
+    ahead: Vec<u8>,
+    kept: Vec<u8>,
+    skipped: u64,
+    throttle: Throttle,
+}
+
+impl Header {
+    /// Read the header of `reader`, returning it and the rest of the payload.
+    /// Bytes are charged to `throttle` before they are read, and those kept
+    /// for the payload given back, to be paid for as the payload is read.
+    fn parse(
+        kind: MediaKind,
+        reader: Box<dyn BufRead>,
+        throttle: Throttle,
+    ) -> io::Result<(Self, Box<dyn BufRead>)> {
+        let mut parser = Parser {
+            reader,
+            ahead: Vec::new(),
+            kept: Vec::new(),
+            skipped: 0,
+            throttle,
+        };
+        match kind {
+            MediaKind::Jpeg => parser.jpeg()?,
+            MediaKind::Mp3 => parser.mp3()?,
+            MediaKind::Flac => parser.flac()?,
+        }
+        let Parser {
+            reader,
+            ahead,
+            mut kept,
+            skipped,
+            throttle,
+        } = parser;
+        kept.extend(ahead);
+        throttle.refund(kept.len() as u64);
+        let header = Self {
+            kept: Cursor::new(kept),
+            skipped,
+        };
+        Ok((header, reader))
+    }
+}
+
+impl Parser {
+    /// Up to the next `len` bytes, fewer only at the end of the file.
+    fn peek(&mut self, len: usize) -> io::Result<&[u8]> {
+        let wanted = len.saturating_sub(self.ahead.len());
+        self.throttle.consume(wanted as u64);
+        let start = self.ahead.len();
+        while self.ahead.len() < len {
+            let buf = self.reader.fill_buf()?;
+            if buf.is_empty() {
+                break;
+            }
+            let read = buf.len().min(len - self.ahead.len());
+            self.ahead.extend_from_slice(&buf[..read]);
+            self.reader.consume(read);
+        }
+        self.throttle
+            .refund((wanted - (self.ahead.len() - start)) as u64);
+        Ok(&self.ahead[..len.min(self.ahead.len())])
+    }
+
+    /// Move up to `len` bytes into the payload.
+    fn keep(&mut self, len: usize) -> io::Result<()> {
+        let len = self.peek(len)?.len();
+        self.kept.extend(self.ahead.drain(..len));
+        Ok(())
+    }
+
+    /// Drop up to `len` bytes from the payload.
+    fn skip(&mut self, len: u64) -> io::Result<()> {
+        let buffered = len.min(self.ahead.len() as u64) as usize;
+        self.ahead.drain(..buffered);
+        let wanted = len - buffered as u64;
+        self.throttle.consume(wanted);
+        let mut rest = (&mut self.reader).take(wanted);
+        let skipped = io::copy(&mut rest, &mut io::sink());
+        self.throttle
+            .refund(wanted - skipped.as_ref().map_or(0, |skipped| *skipped));
+        self.skipped += buffered as u64 + skipped?;
+        Ok(())
+    }
+
+    /// Drop APPn (EXIF, XMP, ICC, ...) and comment segments preceding the scan.
+    fn jpeg(&mut self) -> io::Result<()> {
+        if self.peek(2)? != [0xff, 0xd8] {
+            return Ok(());
+        }
+        self.keep(2)?;
+        loop {
+            let segment = self.peek(4)?;
+            if segment.len() < 4 || segment[0] != 0xff {
+                return Ok(());
+            }
+            let marker = segment[1];
+            if marker == 0xff {
+                // Fill byte
+                self.skip(1)?;
+                continue;
+            }
+            if marker == 0xda {
+                // Start of scan: the remainder is image data
+                return Ok(());
+            }
+            let length = 2 + u16::from_be_bytes([segment[2], segment[3]]) as usize;
+            if (0xe0..=0xef).contains(&marker) || marker == 0xfe {
+                self.skip(length as u64)?;
+            } else {
+                self.keep(length)?;
+            }
+        }
+    }
+
+    /// Drop leading ID3v2 tags; a trailing ID3v1 tag is left to `WithoutId3v1`.
+    fn mp3(&mut self) -> io::Result<()> {
+        loop {
+            let header = self.peek(10)?;
+            if header.len() < 10 || !header.starts_with(b"ID3") {
+                return Ok(());
+            }
+            let size = header[6..10]
+                .iter()
+                .fold(0_u64, |acc, b| (acc << 7) | u64::from(b & 0x7f));
+            let footer = if header[5] & 0x10 != 0 { 10 } else { 0 };
+            self.skip(10 + size + footer)?;
+        }
+    }
+
+    /// Drop the `fLaC` marker and the metadata blocks following it.
+    fn flac(&mut self) -> io::Result<()> {
+        if self.peek(4)? != b"fLaC" {
+            return Ok(());
+        }
+        self.skip(4)?;
+        loop {
+            let block = self.peek(4)?;
+            if block.len() < 4 {
+                return Ok(());
+            }
+            let last = block[0] & 0x80 != 0;
+            let length = u32::from_be_bytes([0, block[1], block[2], block[3]]);
+            self.skip(4 + u64::from(length))?;
+            if last {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Yields its reader's content but for a trailing ID3v1 tag, holding back
+/// the last bytes read until the end shows whether they are one.
+struct WithoutId3v1<R> {
+    reader: R,
+    buffer: Vec<u8>,
+    start: usize,
+    total: u64,
+    eof: bool,
+}
+
+impl<R: Read> WithoutId3v1<R> {
+    fn new(reader: R) -> Self {
+        Self {
+            reader,
+            buffer: Vec::new(),
+            start: 0,
+            total: 0,
+            eof: false,
+        }
+    }
+
+    /// End of the bytes in `buffer` which may be yielded.
+    fn releasable(&self) -> usize {
+        let tag = ID3V1_LEN as usize;
+        let len = self.buffer.len();
+        if !self.eof {
+            len.saturating_sub(tag)
+        } else if self.total >= ID3V1_LEN && self.buffer[len - tag..].starts_with(b"TAG") {
+            len - tag
+        } else {
+            len
+        }
+    }
+}
+
+impl<R: Read> Read for WithoutId3v1<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let releasable = self.releasable();
+            if self.start < releasable {
+                let len = buf.len().min(releasable - self.start);
+                buf[..len].copy_from_slice(&self.buffer[self.start..self.start + len]);
+                self.start += len;
+                return Ok(len);
+            }
+            if self.eof {
+                return Ok(0);
+            }
+            self.buffer.drain(..self.start);
+            self.start = 0;
+            let len = self.buffer.len();
+            self.buffer.resize(len + CHUNK_LEN, 0);
+            let read = self.reader.read(&mut self.buffer[len..]);
+            self.buffer.truncate(len + *read.as_ref().unwrap_or(&0));
+            let read = read?;
+            self.total += read as u64;
+            self.eof = read == 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    const AUDIO: &[u8] = &[0xff, 0xfb, 0x90, 0x64, 1, 2, 3, 4];
+
+    lazy_static::lazy_static! {
+    static ref MP3_FILENAME: &'static Path = Path::new("test_data/media_scratch.mp3");
+    }
+
+    fn strip(kind: MediaKind, data: &[u8]) -> Vec<u8> {
+        let reader = Box::new(Cursor::new(data.to_vec()));
+        let mut payload = Vec::new();
+        MediaGroupComparator::payload(kind, reader, Throttle::default())
+            .unwrap()
+            .read_to_end(&mut payload)
+            .unwrap();
+        payload
+    }
+
+    #[test]
+    fn jpeg_skips_app_segments() {
+        let body = [
+            &[0xff, 0xdb, 0x00, 0x04, 9, 9][..],
+            &[0xff, 0xda, 0x00, 0x02, 1, 2, 3, 0xff, 0xd9],
+        ]
+        .concat();
+        let plain = [&[0xff, 0xd8][..], &body].concat();
+        let tagged = [
+            &[0xff, 0xd8][..],
+            &[0xff, 0xe1, 0x00, 0x06, b'E', b'x', b'i', b'f'],
+            &[0xff, 0xfe, 0x00, 0x03, b'!'],
+            &body,
+        ]
+        .concat();
+
+        assert_eq!(strip(MediaKind::Jpeg, &tagged), plain);
+        assert_eq!(strip(MediaKind::Jpeg, &plain), plain);
+    }
+
+    #[test]
+    fn mp3_skips_id3_tags() {
+        let mut id3v1 = b"TAG".to_vec();
+        id3v1.resize(128, b' ');
+        let tagged = [
+            &b"ID3\x04\x00\x00\x00\x00\x00\x03"[..],
+            &[1, 2, 3],
+            AUDIO,
+            &id3v1,
+        ]
+        .concat();
+
+        assert_eq!(strip(MediaKind::Mp3, &tagged), AUDIO);
+        assert_eq!(strip(MediaKind::Mp3, AUDIO), AUDIO);
+    }
+
+    #[test]
+    fn flac_skips_metadata_blocks() {
+        let tagged = [
+            &b"fLaC"[..],
+            &[0x00, 0x00, 0x00, 0x02, 7, 7],
+            &[0x84, 0x00, 0x00, 0x01, 5],
+            AUDIO,
+        ]
+        .concat();
+
+        assert_eq!(strip(MediaKind::Flac, &tagged), AUDIO);
+    }
+
+    #[test]
+    fn mp3_size_matches_payload() {
+        let mut id3v1 = b"TAG".to_vec();
+        id3v1.resize(128, b' ');
+        let audio = AUDIO.repeat(100_000);
+        let tagged = [
+            &b"ID3\x04\x00\x00\x00\x00\x01\x00"[..],
+            &[7; 128],
+            &audio,
+            &id3v1,
+        ]
+        .concat();
+        fs::write(*MP3_FILENAME, &tagged).unwrap();
+
+        let comparator = MediaGroupComparator::new();
+        let size = comparator.size(&MP3_FILENAME, tagged.len() as u64).unwrap();
+        let mut payload = Vec::new();
+        comparator
+            .open(&MP3_FILENAME)
+            .unwrap()
+            .reader
+            .read_to_end(&mut payload)
+            .unwrap();
+
+        fs::remove_file(*MP3_FILENAME).unwrap();
+        assert_eq!(size, audio.len() as u64);
+        assert!(payload == audio);
+    }
+}
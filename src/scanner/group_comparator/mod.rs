@@ -1,18 +1,55 @@
 use std::{fmt::Debug, path::Path, io::{self, BufRead}};
 
+use crate::Throttle;
+
+mod decompress;
 mod exact;
+mod image;
 mod json;
+mod media;
+pub use decompress::DecompressGroupComparator;
 pub use exact::ExactGroupComparator;
+pub use self::image::ImageGroupComparator;
 pub use json::JsonGroupComparator;
+pub use media::MediaGroupComparator;
 
 pub trait GroupComparator: Debug + Send + Sync {
     fn name(&self) -> &str;
     fn can_analyse(&self, path: &Path) -> bool;
-    fn open(&self, path: &str) -> io::Result<GroupReader>;
+    /// Size of the content yielded by `open`, used to bucket candidate files.
+    fn size(&self, _path: &Path, raw_size: u64) -> io::Result<u64> {
+        Ok(raw_size)
+    }
+    /// Key used to bucket candidate files; defaults to the content size.
+    fn bucket(&self, _path: &Path, size: u64) -> io::Result<u64> {
+        Ok(size)
+    }
+    /// Perceptual fingerprint, for comparators grouping similar rather than
+    /// identical content.
+    fn fingerprint(&self, _path: &Path) -> io::Result<Option<u64>> {
+        Ok(None)
+    }
+    /// Largest Hamming distance between fingerprints still considered a match.
+    fn max_distance(&self) -> u32 {
+        0
+    }
+    /// Whether files matched hold the very same bytes, rather than
+    /// equivalent content once decoded or normalised.
+    fn byte_exact(&self) -> bool {
+        false
+    }
+    fn open(&self, path: &Path) -> io::Result<GroupReader>;
+    /// The `len` bytes of content at `offset`, when they can be read without
+    /// reading everything before them; `None` skips sampling.
+    fn sample(&self, _path: &Path, _offset: u64, _len: usize) -> io::Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+    /// Share the scan's read rate limit, for comparators reading files other
+    /// than through `open`, such as to find their `size`.
+    fn set_throttle(&mut self, _throttle: Throttle) {}
 }
 
 pub struct GroupReader {
     pub reader: Box<dyn BufRead>,
 }
 
-
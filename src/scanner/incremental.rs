@@ -0,0 +1,504 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use super::checkpoint::{self, ScanKey, StoredPath};
+use super::fdupesgroup::FdupesGroup;
+use super::DupeScanner;
+use crate::error::Result;
+use crate::manifest::Digests;
+use crate::throttle;
+use crate::{DupeFile, DupeGroup, ProgressStage};
+
+/// Layout version of [`ScanIndex`], bumped on incompatible changes.
+const INDEX_VERSION: u32 = 2;
+
+/// What a scan found, kept for the next to start from: each file's metadata,
+/// and the groups of identical content every bucket split into, single
+/// files included, with the digests taken of each.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct ScanIndex {
+    version: u32,
+    scan: ScanKey,
+    files: Vec<DupeFile>,
+    buckets: Vec<IndexedBucket>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct IndexedBucket {
+    size: u64,
+    comparator: String,
+    groups: Vec<IndexedGroup>,
+}
+
+/// A group of a bucket: its files, the size of their compared content, and
+/// whichever digests of it were taken, so that later files can be compared
+/// with it without reading its files again.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct IndexedGroup {
+    files: Vec<StoredPath>,
+    size: u64,
+    partialcrc: Option<u16>,
+    fullcrc: Option<u16>,
+    sha256: Option<String>,
+    fingerprint: Option<u64>,
+}
+
+impl IndexedGroup {
+    fn digests(&self) -> Digests {
+        (
+            self.partialcrc,
+            self.fullcrc,
+            self.sha256.clone(),
+            self.fingerprint,
+        )
+    }
+}
+
+/// How the duplicates found differ from those of the previous scan. A group
+/// which gained or lost files is both resolved, as it was, and new.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GroupChanges {
+    /// Groups not found by the previous scan.
+    pub new: Vec<DupeGroup>,
+    /// Groups of the previous scan no longer found, as they were then.
+    pub resolved: Vec<DupeGroup>,
+}
+
+impl ScanIndex {
+    fn new(scan: ScanKey, files: Vec<DupeFile>) -> Self {
+        Self {
+            version: INDEX_VERSION,
+            scan,
+            files,
+            buckets: Vec::new(),
+        }
+    }
+
+    fn is_for(&self, scan: &ScanKey) -> bool {
+        self.version == INDEX_VERSION && self.scan == *scan
+    }
+
+    /// Files of `current` unchanged since this index was taken. Files
+    /// without a modification time, such as archive members, always count
+    /// as changed.
+    fn unchanged(&self, current: &[DupeFile]) -> HashSet<PathBuf> {
+        let stamp = |file: &DupeFile| (file.size, file.modified, file.device, file.inode);
+        let previous = self
+            .files
+            .iter()
+            .map(|file| (&file.path, stamp(file)))
+            .collect::<HashMap<_, _>>();
+        current
+            .iter()
+            .filter(|file| {
+                file.modified.is_some() && previous.get(&file.path) == Some(&stamp(file))
+            })
+            .map(|file| file.path.clone())
+            .collect()
+    }
+
+    fn groups(&self) -> HashMap<(u64, String), Vec<IndexedGroup>> {
+        self.buckets
+            .iter()
+            .map(|bucket| {
+                let key = (bucket.size, bucket.comparator.clone());
+                (key, bucket.groups.clone())
+            })
+            .collect()
+    }
+
+    /// The bucket keys of each file, as the groups recorded hold them.
+    fn keys(&self) -> HashMap<PathBuf, Vec<(u64, String)>> {
+        let mut keys: HashMap<_, Vec<_>> = HashMap::new();
+        for bucket in &self.buckets {
+            for file in bucket.groups.iter().flat_map(|group| &group.files) {
+                keys.entry(file.0.clone())
+                    .or_default()
+                    .push((bucket.size, bucket.comparator.clone()));
+            }
+        }
+        keys
+    }
+
+    fn insert(&mut self, (size, comparator): (u64, String), groups: &[FdupesGroup]) {
+        let groups = groups
+            .iter()
+            .map(|group| {
+                let (partialcrc, fullcrc, sha256, fingerprint) = group.known_digests();
+                IndexedGroup {
+                    files: group.filenames.iter().cloned().map(StoredPath).collect(),
+                    size: group.size,
+                    partialcrc,
+                    fullcrc,
+                    sha256,
+                    fingerprint,
+                }
+            })
+            .collect();
+        self.buckets.push(IndexedBucket {
+            size,
+            comparator,
+            groups,
+        });
+    }
+
+    /// The duplicate groups recorded, largest first as when reported, with
+    /// their files as they were then.
+    fn duplicates(&self) -> Vec<DupeGroup> {
+        let files = self
+            .files
+            .iter()
+            .map(|file| (&file.path, file))
+            .collect::<HashMap<_, _>>();
+        let mut duplicates = Vec::new();
+        for bucket in self.buckets.iter().rev() {
+            for group in bucket.groups.iter().filter(|group| group.files.len() > 1) {
+                let mut duplicate =
+                    DupeGroup::new(0, 0, bucket.size, &bucket.comparator, None, Vec::new(), &[]);
+                duplicate.files = group
+                    .files
+                    .iter()
+                    .filter_map(|file| files.get(&file.0).map(|file| (*file).clone()))
+                    .collect();
+                duplicates.push(duplicate);
+            }
+        }
+        let total = duplicates.len();
+        for (id, duplicate) in duplicates.iter_mut().enumerate() {
+            duplicate.id = id;
+            duplicate.total = total;
+        }
+        duplicates
+    }
+}
+
+impl GroupChanges {
+    fn between(previous: Vec<DupeGroup>, current: Vec<DupeGroup>) -> Self {
+        let files = |group: &DupeGroup| group.filenames().cloned().collect::<BTreeSet<_>>();
+        let before = previous.iter().map(files).collect::<HashSet<_>>();
+        let after = current.iter().map(files).collect::<HashSet<_>>();
+        Self {
+            new: current
+                .into_iter()
+                .filter(|group| !before.contains(&files(group)))
+                .collect(),
+            resolved: previous
+                .into_iter()
+                .filter(|group| !after.contains(&files(group)))
+                .collect(),
+        }
+    }
+}
+
+impl DupeScanner {
+    /// Find duplicate groups as [`Self::find_groups`] does, starting from
+    /// the index of the previous scan at `path`. Only files added or changed
+    /// since are read: each is compared with the digests recorded of the
+    /// groups of unchanged files it could join. The index is then replaced,
+    /// and the groups found compared with those of the previous scan.
+    ///
+    /// Without an index of a scan with the same roots and options, every
+    /// file is compared and all groups found are new.
+    pub fn find_groups_incremental(&self, path: &Path) -> Result<GroupChanges> {
+        if self.config.low_priority {
+            throttle::lower_priority();
+        }
+        let key = ScanKey::new(&self.config, self.group_comparators.keys());
+        let previous = if path.exists() {
+            match checkpoint::load::<ScanIndex>(path) {
+                Ok(index) if index.is_for(&key) => Some(index),
+                Ok(_) => {
+                    warn!(
+                        path = debug(path),
+                        "index is of a different scan, starting afresh"
+                    );
+                    None
+                }
+                Err(e) => {
+                    warn!(error = debug(e), "unreadable index, starting afresh");
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        let files = self.walk_roots()?;
+        let order = files
+            .iter()
+            .enumerate()
+            .map(|(position, (_, path))| (path.clone(), position))
+            .collect::<HashMap<_, _>>();
+        let stamps = files
+            .iter()
+            .map(|(_, path)| DupeFile::new(path.clone(), &self.config.roots))
+            .collect::<Vec<_>>();
+        let (unchanged, mut known) = match &previous {
+            Some(index) => (index.unchanged(&stamps), index.groups()),
+            None => (HashSet::new(), HashMap::new()),
+        };
+        info!("{} of {} files unchanged", unchanged.len(), files.len());
+        let keys = previous.as_ref().map(ScanIndex::keys).unwrap_or_default();
+        let buckets = self.bucket_changed(&files, &unchanged, &keys);
+        self.cancel.check()?;
+
+        let mut index = ScanIndex::new(key, stamps);
+        let mut current = Vec::new();
+        let total = buckets.len();
+        self.progress.comparing(total);
+        for (id, (bucket, filenames)) in buckets.into_iter().rev().enumerate() {
+            let known = known.remove(&bucket).unwrap_or_default();
+            let mut groups = self.regroup(&bucket, &filenames, &known, &unchanged)?;
+            for group in groups.iter_mut() {
+                group.filenames.sort_by_key(|filename| order.get(filename));
+            }
+            groups.sort_by_key(|group| group.filenames.first().and_then(|f| order.get(f)));
+            for group in groups.iter().filter(|group| group.filenames.len() > 1) {
+                let group = group.to_dupe_group(total, id, &self.config.roots);
+                self.tx.send(group.clone())?;
+                current.push(group);
+            }
+            index.insert(bucket, &groups);
+            self.progress.bucket_done();
+        }
+        checkpoint::save(&index, path)?;
+        self.progress.stage(ProgressStage::Done);
+
+        let previous = previous.map(|index| index.duplicates()).unwrap_or_default();
+        let changes = GroupChanges::between(previous, current);
+        info!(
+            "{} new groups, {} resolved groups",
+            changes.new.len(),
+            changes.resolved.len()
+        );
+        Ok(changes)
+    }
+
+    /// Bucket `files` as [`Self::bucket_all_files`] does, taking the keys of
+    /// those `unchanged` from the previous index's `keys` rather than asking
+    /// their comparators again.
+    fn bucket_changed(
+        &self,
+        files: &[(u64, PathBuf)],
+        unchanged: &HashSet<PathBuf>,
+        keys: &HashMap<PathBuf, Vec<(u64, String)>>,
+    ) -> BTreeMap<(u64, String), Vec<PathBuf>> {
+        self.progress.stage(ProgressStage::Bucketing);
+        let mut buckets: BTreeMap<_, Vec<_>> = BTreeMap::new();
+        for (raw_size, path) in files.iter().take_while(|_| !self.cancel.is_cancelled()) {
+            let keys = match keys.get(path) {
+                Some(keys) if unchanged.contains(path) => keys.clone(),
+                _ => self.bucket_keys(*raw_size, path),
+            };
+            for key in keys {
+                buckets.entry(key).or_default().push(path.clone());
+            }
+        }
+        info!("{} buckets", buckets.len());
+        buckets
+    }
+
+    /// Group a bucket's files, keeping the `known` groups of files in
+    /// `unchanged` and comparing only the others with them.
+    fn regroup<'a>(
+        &'a self,
+        (size, comparator_name): &(u64, String),
+        filenames: &[PathBuf],
+        known: &[IndexedGroup],
+        unchanged: &HashSet<PathBuf>,
+    ) -> Result<Vec<FdupesGroup<'a>>> {
+        let comparator = self.comparator(comparator_name)?;
+        let bucketed = filenames.iter().collect::<HashSet<_>>();
+        let mut kept = HashSet::new();
+        let mut groups = Vec::new();
+        for known in known {
+            let mut files = known
+                .files
+                .iter()
+                .map(|file| &file.0)
+                .filter(|file| unchanged.contains(*file) && bucketed.contains(file));
+            if let Some(first) = files.next() {
+                let mut group = self
+                    .new_group(first, known.size, comparator)
+                    .with_digests(known.digests());
+                kept.insert(first);
+                for file in files {
+                    group.add(file);
+                    kept.insert(file);
+                }
+                groups.push(group);
+            }
+        }
+        let changed = filenames
+            .iter()
+            .filter(|file| !kept.contains(file))
+            .cloned()
+            .collect::<Vec<_>>();
+        if groups.is_empty() {
+            return self.match_bucket(*size, comparator_name, &changed);
+        }
+        for filename in &changed {
+            self.cancel.check()?;
+            let mut file = self.new_group(filename, *size, comparator);
+            let mut joined = false;
+            for group in groups.iter_mut() {
+                if self.joins(&mut file, group) {
+                    group.add(filename);
+                    joined = true;
+                    break;
+                }
+            }
+            if !joined {
+                groups.push(file);
+            }
+        }
+        Ok(groups)
+    }
+
+    /// Whether `file` holds the content of `group`, judged by the digests
+    /// recorded of `group` where there are any, so that its files are not
+    /// read again.
+    fn joins<'a>(&self, file: &mut FdupesGroup<'a>, group: &mut FdupesGroup<'a>) -> bool {
+        let joins = match group.known_digests() {
+            (_, _, _, Some(_)) => self.matches(file, group),
+            (partialcrc, Some(fullcrc), Some(sha256), None) => {
+                Self::same_digests(file, partialcrc, fullcrc, &sha256)
+            }
+            _ => return self.same_content(file, group),
+        };
+        joins.unwrap_or_else(|e| {
+            debug!(file = debug(file), error = debug(e), "compare");
+            false
+        })
+    }
+
+    /// Whether `file` has these digests, only read in full should its
+    /// leading block match.
+    fn same_digests(
+        file: &mut FdupesGroup,
+        partialcrc: Option<u16>,
+        fullcrc: u16,
+        sha256: &str,
+    ) -> Result<bool> {
+        if let Some(partialcrc) = partialcrc {
+            if file.partialcrc()? != partialcrc {
+                return Ok(false);
+            }
+        }
+        Ok(file.sha256()? == sha256 && file.fullcrc()? == fullcrc)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Config, DupeGroup, DupeScanner, ExactGroupComparator, GroupChanges};
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::sync::{mpsc, Arc};
+
+    lazy_static::lazy_static! {
+    static ref SCRATCH: &'static Path = Path::new("test_data/incremental_scratch");
+    static ref INDEX: &'static Path = Path::new("test_data/incremental_scratch.json");
+    static ref DIGEST_SCRATCH: &'static Path = Path::new("test_data/incremental_digest_scratch");
+    static ref DIGEST_INDEX: &'static Path = Path::new("test_data/incremental_digest_scratch.json");
+    static ref TEST_DATA1: &'static Path = Path::new("test_data/file1.txt");
+    static ref TEST_DATA2: &'static Path = Path::new("test_data/file2.txt");
+    }
+
+    fn scan(root: &Path, index: &Path) -> (Vec<Vec<PathBuf>>, GroupChanges) {
+        let config = Config {
+            roots: vec![root.to_path_buf()],
+            ..Config::default()
+        };
+        let (tx, rx) = mpsc::channel();
+        let scanner = DupeScanner::new(
+            tx,
+            Arc::new(config),
+            vec![Box::new(ExactGroupComparator::new())],
+        )
+        .unwrap();
+        let changes = scanner.find_groups_incremental(index).unwrap();
+        drop(scanner);
+        (filenames(rx), changes)
+    }
+
+    /// Each group's files, sorted as walk order within a directory varies.
+    fn filenames(groups: impl IntoIterator<Item = DupeGroup>) -> Vec<Vec<PathBuf>> {
+        groups
+            .into_iter()
+            .map(|group| {
+                let mut filenames = group.filenames().cloned().collect::<Vec<_>>();
+                filenames.sort();
+                filenames
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rescan() {
+        fs::create_dir_all(*SCRATCH).unwrap();
+        let files = ["a", "b", "c", "d"].map(|name| SCRATCH.join(name));
+        fs::copy(*TEST_DATA1, &files[0]).unwrap();
+        fs::copy(*TEST_DATA1, &files[1]).unwrap();
+        fs::copy(*TEST_DATA2, &files[2]).unwrap();
+
+        let (groups, changes) = scan(&SCRATCH, &INDEX);
+        assert_eq!(groups, vec![vec![files[0].clone(), files[1].clone()]]);
+        assert_eq!(filenames(changes.new), groups);
+        assert!(changes.resolved.is_empty());
+
+        // Nothing changed, so nothing is new.
+        let (again, changes) = scan(&SCRATCH, &INDEX);
+        assert_eq!(again, groups);
+        assert_eq!(changes, GroupChanges::default());
+
+        // A new copy of the unchanged c, and b no longer a copy of a.
+        fs::copy(*TEST_DATA2, &files[3]).unwrap();
+        fs::write(&files[1], "no longer a copy").unwrap();
+        let (groups, changes) = scan(&SCRATCH, &INDEX);
+        fs::remove_file(*INDEX).unwrap();
+        fs::remove_dir_all(*SCRATCH).unwrap();
+
+        assert_eq!(groups, vec![vec![files[2].clone(), files[3].clone()]]);
+        assert_eq!(filenames(changes.new), groups);
+        assert_eq!(
+            filenames(changes.resolved),
+            vec![vec![files[0].clone(), files[1].clone()]]
+        );
+    }
+
+    #[test]
+    fn trusts_recorded_digests() {
+        fs::create_dir_all(*DIGEST_SCRATCH).unwrap();
+        let files = ["a", "b", "c"].map(|name| DIGEST_SCRATCH.join(name));
+        fs::copy(*TEST_DATA1, &files[0]).unwrap();
+        fs::copy(*TEST_DATA1, &files[1]).unwrap();
+        scan(&DIGEST_SCRATCH, &DIGEST_INDEX);
+
+        // Rewrite a and b behind the index's back, keeping their size and
+        // time: as they count as unchanged, the new copy c is compared with
+        // the digests recorded of them, never with their content.
+        let content = fs::read(*TEST_DATA1).unwrap();
+        for file in &files[..2] {
+            let modified = fs::metadata(file).unwrap().modified().unwrap();
+            fs::write(file, vec![b'x'; content.len()]).unwrap();
+            fs::File::options()
+                .write(true)
+                .open(file)
+                .unwrap()
+                .set_modified(modified)
+                .unwrap();
+        }
+        fs::copy(*TEST_DATA1, &files[2]).unwrap();
+        let (groups, _) = scan(&DIGEST_SCRATCH, &DIGEST_INDEX);
+        fs::remove_file(*DIGEST_INDEX).unwrap();
+        fs::remove_dir_all(*DIGEST_SCRATCH).unwrap();
+
+        assert_eq!(groups, vec![files.to_vec()]);
+    }
+}
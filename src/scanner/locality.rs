@@ -0,0 +1,151 @@
+use std::path::{Path, PathBuf};
+
+use tracing::debug;
+
+use crate::archive;
+
+/// Order in which the files of a bucket are first read.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ReadOrder {
+    /// As the walk found them.
+    #[default]
+    Walk,
+    /// By device, then inode number.
+    Inode,
+    /// By the physical offset of each file's first extent (FIEMAP), where
+    /// the filesystem reports one, then by inode number.
+    Extent,
+}
+
+/// Sort `files` into `order`, so that reading them seeks less. Archive
+/// members sort with their archive.
+pub(crate) fn sort(files: &mut [PathBuf], order: ReadOrder) {
+    if order != ReadOrder::Walk {
+        files.sort_by_cached_key(|path| key(path, order));
+    }
+}
+
+/// Device, physical offset (unknown last) and inode of `path`.
+fn key(path: &Path, order: ReadOrder) -> (u64, u64, u64) {
+    let file = match archive::split(path) {
+        Some((archive, _)) => archive,
+        None => path.to_owned(),
+    };
+    let (device, inode) = match inode(&file) {
+        Ok(inode) => inode,
+        Err(e) => {
+            debug!(path = debug(&file), error = debug(e), "inode");
+            return (u64::MAX, u64::MAX, u64::MAX);
+        }
+    };
+    let offset = match order {
+        ReadOrder::Extent => physical_offset(&file).unwrap_or_else(|e| {
+            debug!(path = debug(&file), error = debug(e), "fiemap");
+            None
+        }),
+        _ => None,
+    };
+    (device, offset.unwrap_or(u64::MAX), inode)
+}
+
+#[cfg(unix)]
+fn inode(path: &Path) -> std::io::Result<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+
+    let metadata = path.metadata()?;
+    Ok((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn inode(_path: &Path) -> std::io::Result<(u64, u64)> {
+    Ok((0, 0))
+}
+
+#[cfg(target_os = "linux")]
+fn physical_offset(path: &Path) -> std::io::Result<Option<u64>> {
+    use std::{fs::File, io, os::unix::io::AsRawFd};
+
+    // _IOWR('f', 11, struct fiemap)
+    const FS_IOC_FIEMAP: libc::c_ulong = 0xc020_660b;
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct FiemapExtent {
+        logical: u64,
+        physical: u64,
+        length: u64,
+        reserved64: [u64; 2],
+        flags: u32,
+        reserved: [u32; 3],
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct Fiemap {
+        start: u64,
+        length: u64,
+        flags: u32,
+        mapped_extents: u32,
+        extent_count: u32,
+        reserved: u32,
+        extents: [FiemapExtent; 1],
+    }
+
+    let file = File::open(path)?;
+    let mut fiemap = Fiemap {
+        length: u64::MAX,
+        extent_count: 1,
+        ..Fiemap::default()
+    };
+    // SAFETY: `fiemap` is laid out as the kernel expects, with room for the
+    // one extent requested, and outlives the call.
+    let result = unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FIEMAP as _, &mut fiemap) };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((fiemap.mapped_extents > 0).then_some(fiemap.extents[0].physical))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn physical_offset(_path: &Path) -> std::io::Result<Option<u64>> {
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{sort, ReadOrder};
+    use std::fs;
+    use std::path::PathBuf;
+
+    #[test]
+    fn sorts() {
+        let files = ["a", "b", "c"]
+            .map(|name| PathBuf::from(format!("test_data/locality_scratch_{name}.txt")));
+        for file in &files {
+            fs::write(file, file.to_string_lossy().as_bytes()).unwrap();
+        }
+        let walked = vec![files[2].clone(), files[0].clone(), files[1].clone()];
+
+        let mut sorted = walked.clone();
+        sort(&mut sorted, ReadOrder::Walk);
+        assert_eq!(sorted, walked);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+
+            let mut by_inode = walked.clone();
+            by_inode.sort_by_key(|file| file.metadata().unwrap().ino());
+            sort(&mut sorted, ReadOrder::Inode);
+            assert_eq!(sorted, by_inode);
+        }
+
+        sort(&mut sorted, ReadOrder::Extent);
+        sorted.sort();
+        assert_eq!(sorted, files);
+
+        for file in &files {
+            fs::remove_file(file).unwrap();
+        }
+    }
+}
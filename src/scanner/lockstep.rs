@@ -0,0 +1,121 @@
+use std::io::{self, BufRead, Read};
+
+use crc::crc16;
+use sha2::{Digest, Sha256};
+use tracing::debug;
+
+use crate::reader::BLOCK_SIZE;
+
+struct Member {
+    index: usize,
+    reader: Box<dyn BufRead>,
+    block: Vec<u8>,
+}
+
+/// Partition `readers` into sets of identical content by reading them all in
+/// lockstep, a block at a time, and splitting a set whenever its blocks
+/// differ. Each file is read at most once.
+///
+/// Sets come back as the indices of their members, in order, with the
+/// CRC-16/X25 and the SHA-256 (in hex) of their content. Unreadable members
+/// are left out, as are singletons unless `keep_singletons` (they are then
+/// read to the end, for their digests).
+pub(crate) fn identical(
+    readers: Vec<(usize, Box<dyn BufRead>)>,
+    keep_singletons: bool,
+) -> Vec<(Vec<usize>, u16, String)> {
+    let members = readers
+        .into_iter()
+        .map(|(index, reader)| Member {
+            index,
+            reader,
+            block: Vec::with_capacity(BLOCK_SIZE),
+        })
+        .collect::<Vec<_>>();
+    let min_len = if keep_singletons { 1 } else { 2 };
+
+    let mut done = Vec::new();
+    let mut active = vec![(members, 0_u16, Sha256::new())];
+    while let Some((mut members, crc, sha256)) = active.pop() {
+        members.retain_mut(
+            |member| match read_block(&mut member.reader, &mut member.block) {
+                Ok(()) => true,
+                Err(e) => {
+                    debug!(index = member.index, error = debug(e), "read");
+                    false
+                }
+            },
+        );
+        if members.len() < min_len {
+            continue;
+        }
+
+        let mut parts: Vec<Vec<Member>> = Vec::new();
+        for member in members {
+            match parts.iter_mut().find(|part| part[0].block == member.block) {
+                Some(part) => part.push(member),
+                None => parts.push(vec![member]),
+            }
+        }
+        for part in parts.into_iter().filter(|part| part.len() >= min_len) {
+            if part[0].block.is_empty() {
+                let indices = part.iter().map(|member| member.index).collect();
+                done.push((indices, crc, format!("{:x}", sha256.clone().finalize())));
+            } else {
+                let crc = crc16::update(crc, &crc16::X25_TABLE, &part[0].block);
+                let mut sha256 = sha256.clone();
+                sha256.update(&part[0].block);
+                active.push((part, crc, sha256));
+            }
+        }
+    }
+    done.sort();
+    done
+}
+
+fn read_block(reader: &mut dyn BufRead, block: &mut Vec<u8>) -> io::Result<()> {
+    block.clear();
+    reader.take(BLOCK_SIZE as u64).read_to_end(block)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{identical, BLOCK_SIZE};
+    use sha2::{Digest, Sha256};
+    use std::io::{BufRead, Cursor};
+
+    fn readers(contents: &[Vec<u8>]) -> Vec<(usize, Box<dyn BufRead>)> {
+        contents
+            .iter()
+            .cloned()
+            .enumerate()
+            .map(|(index, content)| {
+                let reader: Box<dyn BufRead> = Box::new(Cursor::new(content));
+                (index, reader)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn splits() {
+        let a = vec![1_u8; BLOCK_SIZE * 2 + 10];
+        let mut b = a.clone();
+        b[BLOCK_SIZE + 5] = 2;
+        let contents = [a.clone(), b.clone(), a.clone(), vec![3; 10], b];
+
+        let sets = identical(readers(&contents), false);
+        let indices = sets.iter().map(|(set, _, _)| set.clone()).collect::<Vec<_>>();
+        assert_eq!(indices, vec![vec![0, 2], vec![1, 4]]);
+        assert_eq!(sets[0].1, crc::crc16::checksum_x25(&a));
+        assert_eq!(sets[0].2, format!("{:x}", Sha256::digest(&a)));
+
+        let sets = identical(readers(&contents), true);
+        assert_eq!(sets.len(), 3);
+        assert!(sets.contains(&(
+            vec![3],
+            crc::crc16::checksum_x25(&[3; 10]),
+            format!("{:x}", Sha256::digest([3; 10]))
+        )));
+    }
+}
@@ -1,125 +1,336 @@
-use std::io;
+use std::fs;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::{collections::BTreeMap, sync::mpsc::Sender};
+use std::collections::{HashMap, HashSet};
+use std::collections::BTreeMap;
+use std::sync::mpsc::{self, Sender};
+use std::time::{Duration, Instant};
 
 use bool_ext::BoolExt;
-use tracing::{debug, info, trace};
+use tracing::{debug, info, trace, warn};
 use walkdir::WalkDir;
 
-use crate::{Config, DupeMessage};
+use crate::error::{FdupesError, Result};
+use crate::progress::Progress;
+use crate::throttle::{self, Throttle};
+use crate::manifest::ManifestEntry;
+use crate::{archive, CancellationToken, Config, DupeGroup, ProgressEvent, ProgressStage};
 
+mod checkpoint;
+mod dirtree;
 mod fdupesgroup;
+mod fuzzyhash;
 pub(crate) mod group_comparator;
+mod incremental;
+mod locality;
+mod lockstep;
+mod similarity;
+mod watch;
 
+use self::checkpoint::{Checkpoint, ScanKey};
+use self::dirtree::Node;
 use self::fdupesgroup::FdupesGroup;
-use group_comparator::GroupComparator;
+use group_comparator::{ExactGroupComparator, GroupComparator};
+pub use incremental::GroupChanges;
+pub use locality::ReadOrder;
+pub use similarity::SimilarityScanner;
+
+/// Least time between two checkpoints of a scan.
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A candidate file from the walk. `order` is the index of its root, then its
+/// position within that root's walk.
+struct Found {
+    order: (usize, usize),
+    size: u64,
+    path: PathBuf,
+}
+
+/// A bucket's files once the walk is done: matched into candidate groups as
+/// they were found, or left to be read in another order.
+enum Walked<'a> {
+    Matched(Vec<FdupesGroup<'a>>),
+    Deferred(Vec<PathBuf>),
+}
 
 pub struct DupeScanner {
-    tx: Sender<DupeMessage>,
+    tx: Sender<DupeGroup>,
     config: Arc<Config>,
     group_comparators: BTreeMap<String, Box<dyn GroupComparator>>,
+    cancel: CancellationToken,
+    progress: Progress,
+    throttle: Throttle,
 }
 
 impl DupeScanner {
     pub fn new(
-        tx: Sender<DupeMessage>,
+        tx: Sender<DupeGroup>,
         config: Arc<Config>,
         group_comparators: Vec<Box<dyn GroupComparator>>,
-    ) -> Self {
+    ) -> Result<Self> {
         info!("group_comparators: {group_comparators:?}");
-        let group_comparators =
-            group_comparators
-                .into_iter()
-                .fold(BTreeMap::new(), |mut acc, v| {
-                    let name = v.name().to_owned();
-                    debug!(name, comparator = debug(&v), "Add group_comparator");
-                    if let Some(existing) = acc.insert(name.clone(), v) {
-                        panic!(
-                            "Unexpectedly already had comparator with name {}: {:?}",
-                            name, existing
-                        );
-                    }
-                    acc
-                });
-        Self {
+        let throttle = Throttle::new(config.max_read_rate);
+        let mut comparators = BTreeMap::new();
+        for mut comparator in group_comparators {
+            comparator.set_throttle(throttle.clone());
+            let name = comparator.name().to_owned();
+            debug!(name, comparator = debug(&comparator), "Add group_comparator");
+            if comparators.insert(name.clone(), comparator).is_some() {
+                return Err(FdupesError::DuplicateComparator(name));
+            }
+        }
+        Ok(Self {
             tx,
+            throttle,
             config,
-            group_comparators,
-        }
+            group_comparators: comparators,
+            cancel: CancellationToken::new(),
+            progress: Progress::default(),
+        })
+    }
+
+    /// Report progress over `tx` as the scan goes, separately from results.
+    pub fn with_progress(mut self, tx: Sender<ProgressEvent>) -> Self {
+        self.progress = Progress::new(tx);
+        self
+    }
+
+    /// Stop scanning, with `FdupesError::Cancelled`, once `cancel` is cancelled.
+    pub fn with_cancellation(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
     }
 }
 
 impl DupeScanner {
-    pub fn find_groups(&self) {
-        let groups = self.find_files();
-        if tracing::enabled!(tracing::Level::DEBUG) {
-            debug!("{} total groups (by size): {:#?}", groups.len(), &groups);
-        } else {
-            info!("{} total groups (by size)", groups.len());
+    /// Find duplicate groups, sending each on as it is confirmed. In
+    /// low-priority mode this lowers the priority of the calling thread.
+    pub fn find_groups(&self) -> Result<()> {
+        if let Some(path) = &self.config.index {
+            return self.find_groups_incremental(path).map(|_| ());
         }
-
-        self.build_matches(groups).unwrap();
+        if self.config.low_priority {
+            throttle::lower_priority();
+        }
+        if self.config.dirs {
+            self.find_dir_groups()?;
+            self.progress.stage(ProgressStage::Done);
+            return Ok(());
+        }
+        if self.config.unique {
+            self.find_unique()?;
+            self.progress.stage(ProgressStage::Done);
+            return Ok(());
+        }
+        if let Some(path) = &self.config.checkpoint {
+            self.find_groups_checkpointed(path)?;
+            self.progress.stage(ProgressStage::Done);
+            return Ok(());
+        }
+        self.stream_groups()?;
+        self.progress.stage(ProgressStage::Done);
+        Ok(())
     }
 
-    fn send(&self, (id, total, groups): (usize, usize, Vec<FdupesGroup>)) -> Result<(), io::Error> {
-        for bucket in groups {
-            if bucket.filenames.len() > 1 {
-                // TODO: Handle send failures
-                debug!(bucket = debug(&bucket), "send");
-                self.tx.send(bucket.into_dupe_message(total, id)).unwrap();
+    /// Record every file's metadata and digests, once per comparator able to
+    /// analyse it, to `tx` rather than grouping duplicates.
+    pub fn find_manifest(&self, tx: Sender<ManifestEntry>) -> Result<()> {
+        if self.config.low_priority {
+            throttle::lower_priority();
+        }
+        let files = self.walk_roots()?;
+        self.progress.comparing(files.len());
+        for (raw_size, path) in files {
+            self.cancel.check()?;
+            for (size, comparator_name) in self.bucket_keys(raw_size, &path) {
+                let comparator = self.comparator(&comparator_name)?;
+                let mut file = self.new_group(&path, size, comparator);
+                match file.digests() {
+                    Ok(digests) => tx.send(ManifestEntry::new(
+                        path.clone(),
+                        &self.config.roots,
+                        &comparator_name,
+                        size,
+                        digests,
+                    ))?,
+                    Err(e) => warn!(path = debug(&path), error = debug(e), "digests"),
+                }
             }
+            self.progress.bucket_done();
         }
+        self.progress.stage(ProgressStage::Done);
         Ok(())
     }
 
+    /// Walk `root`, sending on each file to compare. Given `entries`, every
+    /// entry walked is sent there too, whatever its size or type.
     fn find_files_root(
-        root: String,
-        non_recursive: bool,
-        min_size: u64,
-    ) -> std::thread::JoinHandle<std::vec::Vec<(u64, std::path::PathBuf)>> {
+        (index, root): (usize, PathBuf),
+        config: &Config,
+        cancel: CancellationToken,
+        progress: Progress,
+        throttle: Throttle,
+        tx: Sender<Found>,
+        entries: Option<Sender<(PathBuf, Node)>>,
+    ) {
+        let (non_recursive, min_size, archives) =
+            (config.non_recursive, config.min_size, config.archives);
         std::thread::spawn(move || {
             info!("scanning {:?}...", root);
-            let r = WalkDir::new(&root)
+            let files = WalkDir::new(&root)
                 .max_depth(non_recursive.map(usize::MAX, 1))
                 .into_iter()
-                .filter_map(|entry| entry.ok())
+                .take_while(|_| !cancel.is_cancelled())
+                .filter_map(|entry| match entry {
+                    Ok(entry) => Some(entry),
+                    Err(e) => {
+                        warn!("skipping {e}");
+                        if let (Some(entries), Some(path)) = (&entries, e.path()) {
+                            let _ = entries.send((path.to_owned(), Node::Other));
+                        }
+                        None
+                    }
+                })
+                .inspect(|entry| {
+                    if let Some(entries) = &entries {
+                        let node = match dirtree::node(entry) {
+                            // Not walked, so its contents are unknown.
+                            Node::Dir if non_recursive && entry.depth() > 0 => Node::Other,
+                            node => node,
+                        };
+                        let _ = entries.send((entry.path().to_owned(), node));
+                    }
+                })
                 .filter(|entry| entry.path().is_file())
-                .filter(|entry| entry.metadata().unwrap().len() >= min_size)
-                .map(|entry| (entry.metadata().unwrap().len(), entry.path().to_owned()))
-                .collect();
+                .filter_map(|entry| match entry.metadata() {
+                    Ok(metadata) => Some((metadata.len(), entry.path().to_owned())),
+                    Err(e) => {
+                        warn!("skipping {e}");
+                        None
+                    }
+                })
+                .flat_map(|file| {
+                    let mut files = vec![];
+                    if archives && archive::is_archive(&file.1) {
+                        match archive::members(&file.1, &throttle) {
+                            Ok(members) => files.extend(members),
+                            Err(e) => debug!(archive = debug(&file.1), error = debug(e), "members"),
+                        }
+                    }
+                    files.push(file);
+                    files
+                })
+                .filter(|(size, _)| *size >= min_size);
+            for (position, (size, path)) in files.enumerate() {
+                progress.discovered(1);
+                let found = Found {
+                    order: (index, position),
+                    size,
+                    path,
+                };
+                if tx.send(found).is_err() {
+                    break;
+                }
+            }
             info!("scanning {:?} complete.", root);
-            r
-        })
+        });
     }
 
-    fn find_files(&self) -> BTreeMap<(u64, String), Vec<PathBuf>> {
+    /// Walk every root on a thread of its own, yielding files in walk order:
+    /// each root's in turn, while later roots are walked in the background.
+    fn spawn_walkers(
+        config: &Config,
+        cancel: &CancellationToken,
+        progress: &Progress,
+        throttle: &Throttle,
+        entries: Option<Sender<(PathBuf, Node)>>,
+    ) -> impl Iterator<Item = Found> {
         info!(
             "find all files in {:?} (non-recursive: {}, min_size: {})",
-            self.config.roots, self.config.non_recursive, self.config.min_size
+            config.roots, config.non_recursive, config.min_size
         );
+        let mut walkers = Vec::new();
+        for root in config.roots.iter().cloned().enumerate() {
+            let (tx, rx) = mpsc::channel();
+            Self::find_files_root(
+                root,
+                config,
+                cancel.clone(),
+                progress.clone(),
+                throttle.clone(),
+                tx,
+                entries.clone(),
+            );
+            walkers.push(rx);
+        }
+        walkers.into_iter().flatten()
+    }
 
-        let all_groups = self
-            .config
-            .roots
+    /// Every file under the roots, in walk order.
+    pub(crate) fn walk(
+        config: &Config,
+        cancel: &CancellationToken,
+        progress: &Progress,
+        throttle: &Throttle,
+    ) -> Vec<(u64, PathBuf)> {
+        Self::spawn_walkers(config, cancel, progress, throttle, None)
+            .map(|found| (found.size, found.path))
+            .collect()
+    }
+
+    fn walk_roots(&self) -> Result<Vec<(u64, PathBuf)>> {
+        self.progress.stage(ProgressStage::Walking);
+        let files = Self::walk(&self.config, &self.cancel, &self.progress, &self.throttle);
+        self.cancel.check()?;
+        Ok(files)
+    }
+
+    /// Buckets `path` belongs in: one per comparator able to analyse it.
+    fn bucket_keys(&self, raw_size: u64, path: &Path) -> Vec<(u64, String)> {
+        if raw_size < self.config.min_size {
+            return Vec::new();
+        }
+        self.group_comparators
             .iter()
-            .map(|r| {
-                Self::find_files_root(
-                    r.to_owned(),
-                    self.config.non_recursive,
-                    self.config.min_size,
-                )
+            .filter(|(_, comparator)| comparator.can_analyse(path))
+            .filter_map(|(comparator_name, comparator)| {
+                match comparator
+                    .size(path, raw_size)
+                    .and_then(|size| comparator.bucket(path, size))
+                {
+                    Ok(size) => Some((size, comparator_name.to_owned())),
+                    Err(e) => {
+                        debug!(path = debug(path), comparator_name, error = debug(e), "size");
+                        None
+                    }
+                }
             })
-            .filter_map(|h| h.join().ok())
-            .flatten()
+            .collect()
+    }
+
+    /// Buckets shared by more than one file.
+    fn bucket_files<'a>(
+        &self,
+        files: impl IntoIterator<Item = &'a (u64, PathBuf)>,
+    ) -> BTreeMap<(u64, String), Vec<PathBuf>> {
+        self.bucket_all_files(files)
+            .into_iter()
+            .filter(|(_, files)| files.len() > 1)
+            .collect()
+    }
+
+    /// Every bucket, including those of a single file.
+    fn bucket_all_files<'a>(
+        &self,
+        files: impl IntoIterator<Item = &'a (u64, PathBuf)>,
+    ) -> BTreeMap<(u64, String), Vec<PathBuf>> {
+        self.progress.stage(ProgressStage::Bucketing);
+        let all_groups = files
+            .into_iter()
+            .take_while(|_| !self.cancel.is_cancelled())
             .fold(BTreeMap::new(), |mut acc, (raw_size, path)| {
-                for (comparator_name, comparator) in &self.group_comparators {
-                    if raw_size >= self.config.min_size && comparator.can_analyse(&path) {
-                        //TODO Comparator generate size (e.g. post-json normalization)
-                        acc.entry((raw_size, comparator_name.to_owned()))
-                            .or_insert_with(Vec::new)
-                            .push(path.clone());
-                    }
+                for key in self.bucket_keys(*raw_size, path) {
+                    acc.entry(key).or_insert_with(Vec::new).push(path.clone());
                 }
                 acc
             });
@@ -133,51 +344,510 @@ impl DupeScanner {
             info!("{} non-unique groups (by size)", all_groups.len());
         }
         all_groups
-            .into_iter()
-            .filter(|(_, files)| files.len() > 1)
-            .collect()
     }
 
-    fn build_matches(
+    /// Bucket files and match them against their bucket's candidates as the
+    /// walk finds them, so that reading overlaps walking. A bucket's first
+    /// file is only read once a second turns up. Once the walk is done each
+    /// bucket's candidates are verified, and its groups sent, once. Groups
+    /// hold their files in walk order.
+    ///
+    /// In low-memory mode a first walk finds which bucket keys recur, and
+    /// files with any other key are skipped: only candidates are held.
+    ///
+    /// With a read order other than the walk's, each bucket is only read
+    /// once the walk is done, sorted into that order.
+    fn stream_groups(&self) -> Result<()> {
+        let (recurring, walk_progress) = if self.config.low_memory {
+            // Files were already counted by the first walk.
+            (Some(self.recurring_keys()?), Progress::default())
+        } else {
+            (None, self.progress.clone())
+        };
+        self.progress.stage(ProgressStage::Walking);
+        let found =
+            Self::spawn_walkers(&self.config, &self.cancel, &walk_progress, &self.throttle, None);
+        self.stream_found(found, recurring.as_ref())
+    }
+
+    fn stream_found(
         &self,
-        groups: BTreeMap<(u64, String), Vec<PathBuf>>,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let total = groups.len();
-        // TODO: What to do when multiple comparators match same group (or partial groups)?
-        for (id, ((size, comparator_name), filenames)) in groups.iter().rev().enumerate() {
-            debug!(
-                "build matches ({}, {}): {} files",
+        found: impl IntoIterator<Item = Found>,
+        recurring: Option<&BTreeMap<String, HashSet<u64>>>,
+    ) -> Result<()> {
+        let mut buckets: BTreeMap<(u64, String), Vec<FdupesGroup>> = BTreeMap::new();
+        let mut deferred: BTreeMap<(u64, String), Vec<PathBuf>> = BTreeMap::new();
+        let mut order = HashMap::new();
+        for found in found {
+            self.cancel.check()?;
+            for key in self.bucket_keys(found.size, &found.path) {
+                if recurring.is_some_and(|recurring| {
+                    !recurring
+                        .get(&key.1)
+                        .is_some_and(|sizes| sizes.contains(&key.0))
+                }) {
+                    continue;
+                }
+                order.insert(found.path.clone(), found.order);
+                if self.config.read_order != ReadOrder::Walk {
+                    deferred.entry(key).or_default().push(found.path.clone());
+                    continue;
+                }
+                let comparator = self.comparator(&key.1)?;
+                let groups = buckets.entry(key.clone()).or_default();
+                self.update_matches(&found.path, key.0, comparator, groups);
+            }
+        }
+        self.cancel.check()?;
+
+        let mut buckets = buckets
+            .into_iter()
+            .rev()
+            .filter(|(_, groups)| groups.iter().any(|group| group.filenames.len() > 1))
+            .map(|(key, groups)| (key, Walked::Matched(groups)))
+            .chain(
+                deferred
+                    .into_iter()
+                    .rev()
+                    .filter(|(_, filenames)| filenames.len() > 1)
+                    .map(|(key, filenames)| (key, Walked::Deferred(filenames))),
+            )
+            .collect::<Vec<_>>();
+        buckets.sort_by_key(|((_, comparator_name), _)| !self.is_byte_exact(comparator_name));
+        let total = buckets.len();
+        self.progress.comparing(total);
+        let mut copies: HashSet<PathBuf> = HashSet::new();
+        for (id, ((size, comparator_name), bucket)) in buckets.into_iter().enumerate() {
+            let byte_exact = self.is_byte_exact(&comparator_name);
+            let mut groups = match bucket {
+                Walked::Matched(mut candidates) => {
+                    if !byte_exact {
+                        for copy in &copies {
+                            candidates.iter_mut().for_each(|group| group.remove(copy));
+                        }
+                    }
+                    self.verify(candidates)?
+                }
+                Walked::Deferred(mut filenames) => {
+                    if !byte_exact {
+                        filenames.retain(|filename| !copies.contains(filename));
+                    }
+                    self.match_bucket(size, &comparator_name, &filenames)?
+                }
+            };
+            groups.retain(|group| group.filenames.len() > 1);
+            for group in groups.iter_mut() {
+                group.filenames.sort_by_key(|filename| order.get(filename));
+            }
+            groups.sort_by_key(|group| group.filenames.first().and_then(|f| order.get(f)));
+            for group in groups {
+                if byte_exact {
+                    copies.extend(group.filenames[1..].iter().cloned());
+                }
+                self.tx
+                    .send(group.into_dupe_group(total, id, &self.config.roots))?;
+            }
+            self.progress.bucket_done();
+        }
+        Ok(())
+    }
+
+    /// Whether `comparator_name` matches byte-identical files. Files such a
+    /// comparator finds to be copies of another are left out of other
+    /// comparators' buckets, rather than reported twice, so its buckets are
+    /// compared first.
+    fn is_byte_exact(&self, comparator_name: &str) -> bool {
+        self.group_comparators
+            .get(comparator_name)
+            .is_some_and(|comparator| comparator.byte_exact())
+    }
+
+    /// First walk of a low-memory scan: the bucket keys of each comparator
+    /// shared by more than one file. Sizes are kept, never paths.
+    fn recurring_keys(&self) -> Result<BTreeMap<String, HashSet<u64>>> {
+        self.progress.stage(ProgressStage::Counting);
+        let mut seen: BTreeMap<String, HashMap<u64, bool>> = BTreeMap::new();
+        let found =
+            Self::spawn_walkers(&self.config, &self.cancel, &self.progress, &self.throttle, None);
+        for found in found {
+            self.cancel.check()?;
+            for (size, comparator_name) in self.bucket_keys(found.size, &found.path) {
+                seen.entry(comparator_name)
+                    .or_default()
+                    .entry(size)
+                    .and_modify(|recurs| *recurs = true)
+                    .or_insert(false);
+            }
+        }
+        self.cancel.check()?;
+        let recurring = seen
+            .into_iter()
+            .map(|(comparator_name, sizes)| {
+                let sizes = sizes
+                    .into_iter()
+                    .filter_map(|(size, recurs)| recurs.then_some(size))
+                    .collect::<HashSet<_>>();
+                (comparator_name, sizes)
+            })
+            .collect::<BTreeMap<_, _>>();
+        info!(
+            "{} recurring sizes",
+            recurring.values().map(HashSet::len).sum::<usize>()
+        );
+        Ok(recurring)
+    }
+
+    /// Walk and bucket up front, then compare each bucket in turn, saving
+    /// progress to the checkpoint at `path` as it goes. When resuming from a
+    /// checkpoint of the same scan, its buckets are reused, and those already
+    /// compared are not read again: their saved groups are sent, less any
+    /// file since removed. A finished scan removes its checkpoint.
+    fn find_groups_checkpointed(&self, path: &Path) -> Result<()> {
+        let key = ScanKey::new(&self.config, self.group_comparators.keys());
+        let resumed = if self.config.resume && path.exists() {
+            match Checkpoint::load(path) {
+                Ok(checkpoint) if checkpoint.is_for(&key) => Some(checkpoint),
+                Ok(_) => {
+                    warn!(
+                        path = debug(path),
+                        "checkpoint is of a different scan, starting afresh"
+                    );
+                    None
+                }
+                Err(e) => {
+                    warn!(error = debug(e), "unreadable checkpoint, starting afresh");
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        let mut checkpoint = match resumed {
+            Some(checkpoint) => {
+                info!(path = debug(path), "resuming from checkpoint");
+                checkpoint
+            }
+            None => {
+                let files = self.walk_roots()?;
+                let buckets = self.bucket_files(&files);
+                self.cancel.check()?;
+                let checkpoint = Checkpoint::new(key, &buckets);
+                checkpoint.save(path)?;
+                checkpoint
+            }
+        };
+
+        let result = self.compare_checkpointed(&mut checkpoint, path);
+        match result {
+            Ok(()) => fs::remove_file(path).map_err(|e| FdupesError::io(path, e)),
+            Err(e) => {
+                // Keep what was done for the next run to resume from.
+                checkpoint.save(path)?;
+                Err(e)
+            }
+        }
+    }
+
+    fn compare_checkpointed(&self, checkpoint: &mut Checkpoint, path: &Path) -> Result<()> {
+        let buckets = checkpoint.buckets();
+        let total = buckets.len();
+        self.progress.comparing(total);
+        let mut saved = Instant::now();
+        for (id, (key, filenames)) in buckets.into_iter().rev().enumerate() {
+            if let Some(saved) = checkpoint.completed(&key) {
+                for group in saved {
+                    let filenames = group
+                        .files
+                        .iter()
+                        .map(|file| file.0.clone())
+                        .filter(|f| archive::is_member(f) || f.symlink_metadata().is_ok())
+                        .collect::<Vec<_>>();
+                    if filenames.len() > 1 {
+                        self.tx.send(DupeGroup::new(
+                            id,
+                            total,
+                            key.0,
+                            &key.1,
+                            group.digest.clone(),
+                            filenames,
+                            &self.config.roots,
+                        ))?;
+                    }
+                }
+                self.progress.bucket_done();
+                continue;
+            }
+            let groups = self
+                .match_bucket(key.0, &key.1, &filenames)?
+                .into_iter()
+                .filter(|group| group.filenames.len() > 1)
+                .map(|group| group.into_dupe_group(total, id, &self.config.roots))
+                .collect::<Vec<_>>();
+            for group in &groups {
+                self.tx.send(group.clone())?;
+            }
+            checkpoint.complete(key, &groups);
+            self.progress.bucket_done();
+            if saved.elapsed() >= CHECKPOINT_INTERVAL {
+                checkpoint.save(path)?;
+                saved = Instant::now();
+            }
+        }
+        Ok(())
+    }
+
+    /// Report, as single-file groups, files under the first root with no
+    /// identical copy under any other root; given only one root, files with
+    /// no copy at all. Buckets which cannot hold such a copy are not read.
+    fn find_unique(&self) -> Result<()> {
+        self.progress.stage(ProgressStage::Walking);
+        let found =
+            Self::spawn_walkers(&self.config, &self.cancel, &self.progress, &self.throttle, None)
+                .collect::<Vec<_>>();
+        self.cancel.check()?;
+        let primary = found
+            .iter()
+            .filter(|found| found.order.0 == 0)
+            .map(|found| found.path.clone())
+            .collect::<HashSet<_>>();
+        let files = found
+            .into_iter()
+            .map(|found| (found.size, found.path))
+            .collect::<Vec<_>>();
+        let buckets = self.bucket_all_files(&files);
+        self.cancel.check()?;
+
+        let single_root = self.config.roots.len() < 2;
+        let mut bucketed = HashMap::new();
+        let mut copied = HashSet::new();
+        self.progress.comparing(buckets.len());
+        for ((size, comparator_name), filenames) in buckets {
+            for filename in filenames.iter().filter(|f| primary.contains(*f)) {
+                bucketed
+                    .entry(filename.clone())
+                    .or_insert_with(|| (size, comparator_name.clone()));
+            }
+            let primaries = filenames.iter().filter(|f| primary.contains(*f)).count();
+            let comparable = if single_root {
+                filenames.len() > 1
+            } else {
+                primaries > 0 && primaries < filenames.len()
+            };
+            if comparable {
+                for group in self.match_bucket(size, &comparator_name, &filenames)? {
+                    if group.filenames.len() > 1
+                        && (single_root || group.filenames.iter().any(|f| !primary.contains(f)))
+                    {
+                        copied.extend(
+                            group
+                                .filenames
+                                .into_iter()
+                                .filter(|f| primary.contains(f)),
+                        );
+                    }
+                }
+            }
+            self.progress.bucket_done();
+        }
+
+        let unique = files
+            .into_iter()
+            .filter_map(|(_, path)| {
+                let key = bucketed.remove(&path)?;
+                (!copied.contains(&path)).then_some((path, key))
+            })
+            .collect::<Vec<_>>();
+        let total = unique.len();
+        for (id, (path, (size, comparator_name))) in unique.into_iter().enumerate() {
+            self.tx.send(DupeGroup::new(
+                id,
+                total,
                 size,
-                comparator_name,
-                filenames.len()
-            );
-            let mut result = Vec::new();
-            for filename in filenames {
-                self.update_matches(filename, *size, comparator_name, &mut result);
+                &comparator_name,
+                None,
+                vec![path],
+                &self.config.roots,
+            ))?;
+        }
+        Ok(())
+    }
+
+    fn comparator(&self, comparator_name: &str) -> Result<&dyn GroupComparator> {
+        self.group_comparators
+            .get(comparator_name)
+            .map(Box::as_ref)
+            .ok_or_else(|| FdupesError::UnknownComparator(comparator_name.to_owned()))
+    }
+
+    fn match_bucket<'a>(
+        &'a self,
+        size: u64,
+        comparator_name: &str,
+        filenames: &[PathBuf],
+    ) -> Result<Vec<FdupesGroup<'a>>> {
+        self.match_files(size, self.comparator(comparator_name)?, filenames)
+    }
+
+    fn match_files<'a>(
+        &'a self,
+        size: u64,
+        comparator: &'a dyn GroupComparator,
+        filenames: &[PathBuf],
+    ) -> Result<Vec<FdupesGroup<'a>>> {
+        let comparator_name = comparator.name();
+        debug!(
+            "build matches ({}, {}): {} files",
+            size,
+            comparator_name,
+            filenames.len()
+        );
+        let mut filenames = filenames.to_vec();
+        locality::sort(&mut filenames, self.config.read_order);
+        let mut result = Vec::new();
+        for filename in &filenames {
+            self.cancel.check()?;
+            self.update_matches(filename, size, comparator, &mut result);
+        }
+        let result = self.verify(result)?;
+        debug!(
+            " => {:?}",
+            result.iter().map(|r| r.filenames.len()).collect::<Vec<_>>()
+        );
+        Ok(result)
+    }
+
+    /// Report duplicate directory trees, then the duplicate files not
+    /// already covered by one of those directories.
+    fn find_dir_groups(&self) -> Result<()> {
+        // Every entry counts towards a tree, not only the files walked for comparison.
+        self.progress.stage(ProgressStage::Walking);
+        let (entries_tx, entries) = mpsc::channel();
+        let files = Self::spawn_walkers(
+            &self.config,
+            &self.cancel,
+            &self.progress,
+            &self.throttle,
+            Some(entries_tx),
+        )
+        .map(|found| (found.size, found.path))
+        .collect::<Vec<_>>();
+        self.cancel.check()?;
+        let entries = entries.into_iter().collect::<Vec<_>>();
+        let buckets = self.bucket_files(&files);
+        self.cancel.check()?;
+
+        // Only byte-for-byte matches make trees identical; similar images or
+        // media with the same payload do not. Without a byte-exact comparator,
+        // as with --decompress, files are compared byte for byte for that too.
+        let exact = ExactGroupComparator::new().with_io(self.config.io);
+        let mut exact_buckets: BTreeMap<u64, Vec<PathBuf>> = BTreeMap::new();
+        if !self.group_comparators.values().any(|c| c.byte_exact()) {
+            for (size, path) in &files {
+                exact_buckets.entry(*size).or_default().push(path.clone());
             }
-            debug!(
-                " => {:?}",
-                result.iter().map(|r| r.filenames.len()).collect::<Vec<_>>()
-            );
-            self.send((id, total, result))?;
+            exact_buckets.retain(|_, files| files.len() > 1);
+        }
+        let mut content = HashMap::new();
+        let mut content_id = 0;
+        let mut file_groups = Vec::new();
+        self.progress.comparing(buckets.len() + exact_buckets.len());
+        for ((size, comparator_name), filenames) in buckets.iter().rev() {
+            let byte_exact = self.is_byte_exact(comparator_name);
+            for group in self.match_bucket(*size, comparator_name, filenames)? {
+                if byte_exact {
+                    for filename in &group.filenames {
+                        content.entry(filename.clone()).or_insert(content_id);
+                    }
+                    content_id += 1;
+                }
+                if group.filenames.len() > 1 {
+                    file_groups.push(group);
+                }
+            }
+            self.progress.bucket_done();
+        }
+        for (size, filenames) in exact_buckets.iter().rev() {
+            for group in self.match_files(*size, &exact, filenames)? {
+                for filename in &group.filenames {
+                    content.entry(filename.clone()).or_insert(content_id);
+                }
+                content_id += 1;
+            }
+            self.progress.bucket_done();
+        }
+
+        let dir_groups =
+            dirtree::duplicate_dirs(&self.config.roots, &entries, &content, self.config.dir_names);
+        let duplicated = dir_groups
+            .iter()
+            .flat_map(|(_, dirs)| dirs.iter().map(PathBuf::as_path))
+            .collect::<HashSet<_>>();
+        let file_groups = file_groups
+            .into_iter()
+            .filter(|group| {
+                !group.filenames.iter().all(|filename| {
+                    filename
+                        .ancestors()
+                        .skip(1)
+                        .any(|dir| duplicated.contains(dir))
+                })
+            })
+            .collect::<Vec<_>>();
+        info!(
+            "{} duplicate directory groups, {} remaining file groups",
+            dir_groups.len(),
+            file_groups.len()
+        );
+
+        let total = dir_groups.len() + file_groups.len();
+        for (id, (size, dirs)) in dir_groups.iter().enumerate() {
+            debug!(dirs = debug(dirs), "send");
+            self.tx.send(DupeGroup::new(
+                id,
+                total,
+                *size,
+                "directory",
+                None,
+                dirs.clone(),
+                &self.config.roots,
+            ))?;
+        }
+        for (id, group) in file_groups.into_iter().enumerate() {
+            debug!(group = debug(&group), "send");
+            self.tx.send(group.into_dupe_group(
+                total,
+                dir_groups.len() + id,
+                &self.config.roots,
+            ))?;
         }
         Ok(())
     }
 
+    /// A group of just `filename`, read as this scan is configured to.
+    fn new_group<'a>(
+        &self,
+        filename: &Path,
+        size: u64,
+        comparator: &'a dyn GroupComparator,
+    ) -> FdupesGroup<'a> {
+        FdupesGroup::new(filename, size, comparator)
+            .with_progress(self.progress.clone())
+            .with_throttle(self.throttle.clone())
+            .with_samples(self.config.samples)
+    }
+
     fn update_matches<'a>(
         &'a self,
         filename: &Path,
         size: u64,
-        comparator_name: &str,
+        comparator: &'a dyn GroupComparator,
         result: &mut Vec<FdupesGroup<'a>>,
     ) {
-        let comparator = self.group_comparators.get(comparator_name).unwrap();
-        //panic!("Update matches for {comparator_name}: {comparator:?}");
         //TODO Restriction to comparator logics
-        let mut file = FdupesGroup::new(filename, size, comparator.as_ref());
+        let mut file = self.new_group(filename, size, comparator);
         for r in result
             .iter_mut()
-            .filter(|g| g.comparator.name() == comparator_name)
+            .filter(|g| g.comparator.name() == comparator.name())
         {
             match self.matches(&mut file, r) {
                 Ok(true) => {
@@ -195,31 +865,151 @@ impl DupeScanner {
         result.push(file);
     }
 
+    /// Whether `file` is a candidate for `group`: similar fingerprints, or
+    /// the same leading block, then last block, then sampled blocks. Candidates are confirmed by [`Self::verify`].
     fn matches<'a, 'b>(
         &self,
         file: &mut FdupesGroup<'a>,
         group: &mut FdupesGroup<'b>,
-    ) -> io::Result<bool>
+    ) -> Result<bool>
     where
         'a: 'b,
         'b: 'a,
     {
         trace!("Compared {file:?} vs {group:?}");
-        let filecrc = file.partialcrc()?;
-        let groupcrc = group.partialcrc()?;
-        if filecrc != groupcrc {
-            return Ok(false);
+        if let (Some(a), Some(b)) = (file.fingerprint()?, group.fingerprint()?) {
+            return Ok((a ^ b).count_ones() <= file.comparator.max_distance());
         }
-        let filecrc = file.fullcrc()?;
-        let groupcrc = group.fullcrc()?;
-        if filecrc != groupcrc {
+        if file.partialcrc()? != group.partialcrc()? {
             return Ok(false);
         }
-        if file == group {
-            Ok(true)
-        } else {
-            Ok(false)
+        for stage in 0..file.stages() {
+            if file.samplecrc(stage)? != group.samplecrc(stage)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Whether `file` holds the same content as `group`: matching
+    /// fingerprints, or byte for byte.
+    fn same_content<'a>(&self, file: &mut FdupesGroup<'a>, group: &mut FdupesGroup<'a>) -> bool {
+        match self.matches(file, group) {
+            Ok(true) => matches!(file.fingerprint(), Ok(Some(_))) || *group == *file,
+            Ok(false) => false,
+            Err(e) => {
+                debug!(file = debug(file), error = debug(e), "compare");
+                false
+            }
+        }
+    }
+
+    /// Split candidate groups into groups of identical files, comparing each
+    /// group's files together rather than pairwise.
+    fn verify<'a>(&self, groups: Vec<FdupesGroup<'a>>) -> Result<Vec<FdupesGroup<'a>>> {
+        let mut verified = Vec::new();
+        for group in groups {
+            self.cancel.check()?;
+            verified.extend(group.split(self.config.max_open));
         }
-        //Ok(file == group)
+        Ok(verified)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Found;
+    use crate::{Config, DupeScanner, ExactGroupComparator};
+    use std::fs;
+    use std::path::Path;
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+    use std::time::Duration;
+
+    lazy_static::lazy_static! {
+    static ref SCRATCH: &'static Path = Path::new("test_data/stream_scratch");
+    static ref MISMATCH_SCRATCH: &'static Path = Path::new("test_data/stream_mismatch_scratch");
+    static ref TEST_DATA1: &'static Path = Path::new("test_data/file1.txt");
+    }
+
+    #[test]
+    fn sends_each_group_once() {
+        fs::create_dir_all(*SCRATCH).unwrap();
+        let copies = ["a", "b", "c"].map(|name| SCRATCH.join(name));
+        for copy in &copies {
+            fs::copy(*TEST_DATA1, copy).unwrap();
+        }
+
+        let (tx, rx) = mpsc::channel();
+        let (progress_tx, progress) = mpsc::channel();
+        let scanner = DupeScanner::new(
+            tx,
+            Arc::new(Config::default()),
+            vec![Box::new(ExactGroupComparator::new())],
+        )
+        .unwrap()
+        .with_progress(progress_tx);
+        let (walk, found) = mpsc::channel();
+        let streaming = thread::spawn(move || scanner.stream_found(found, None));
+        let size = fs::metadata(*TEST_DATA1).unwrap().len();
+        for (position, path) in copies.iter().enumerate() {
+            walk.send(Found {
+                order: (0, position),
+                size,
+                path: path.clone(),
+            })
+            .unwrap();
+        }
+
+        // Nothing is sent while the walk may still grow a group.
+        assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+        drop(walk);
+        streaming.join().unwrap().unwrap();
+        let groups = rx.iter().collect::<Vec<_>>();
+        fs::remove_dir_all(*SCRATCH).unwrap();
+
+        assert_eq!(groups.len(), 1);
+        assert_eq!(
+            groups[0].filenames().cloned().collect::<Vec<_>>(),
+            copies.to_vec()
+        );
+        assert_eq!((groups[0].id, groups[0].total), (0, 1));
+        assert!(progress.try_iter().any(|event| event.buckets_total == 1));
+    }
+
+    #[test]
+    fn compares_past_a_failed_candidate() {
+        // Too small to sample: only the full compare tells 1_a apart.
+        fs::create_dir_all(*MISMATCH_SCRATCH).unwrap();
+        let content = vec![b'x'; 1600];
+        let mut differs = content.clone();
+        differs[1500] = b'y';
+        let files = ["1_a", "2_b", "3_c"].map(|name| MISMATCH_SCRATCH.join(name));
+        fs::write(&files[0], &differs).unwrap();
+        fs::write(&files[1], &content).unwrap();
+        fs::write(&files[2], &content).unwrap();
+
+        let (tx, rx) = mpsc::channel();
+        let scanner = DupeScanner::new(
+            tx,
+            Arc::new(Config::default()),
+            vec![Box::new(ExactGroupComparator::new())],
+        )
+        .unwrap();
+        let found = files.iter().enumerate().map(|(position, path)| Found {
+            order: (0, position),
+            size: content.len() as u64,
+            path: path.clone(),
+        });
+        scanner.stream_found(found, None).unwrap();
+        drop(scanner);
+        let groups = rx.iter().collect::<Vec<_>>();
+        fs::remove_dir_all(*MISMATCH_SCRATCH).unwrap();
+
+        assert_eq!(groups.len(), 1);
+        assert_eq!(
+            groups[0].filenames().cloned().collect::<Vec<_>>(),
+            files[1..].to_vec()
+        );
     }
 }
@@ -0,0 +1,139 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+
+use tracing::{debug, info};
+
+use super::fuzzyhash::FuzzyHash;
+use super::DupeScanner;
+use crate::error::Result;
+use crate::progress::Progress;
+use crate::throttle::{self, Throttle};
+use crate::{CancellationToken, Config, SimilarMessage};
+
+/// Reports clusters of near-duplicate files, linked by fuzzy hash similarity.
+pub struct SimilarityScanner {
+    tx: Sender<SimilarMessage>,
+    config: Arc<Config>,
+    threshold: u32,
+    cancel: CancellationToken,
+    throttle: Throttle,
+}
+
+impl SimilarityScanner {
+    pub fn new(tx: Sender<SimilarMessage>, config: Arc<Config>, threshold: u32) -> Self {
+        Self {
+            tx,
+            throttle: Throttle::new(config.max_read_rate),
+            config,
+            threshold,
+            cancel: CancellationToken::new(),
+        }
+    }
+
+    /// Stop scanning, with `FdupesError::Cancelled`, once `cancel` is cancelled.
+    pub fn with_cancellation(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// In low-priority mode this lowers the priority of the calling thread.
+    pub fn find_clusters(&self) -> Result<()> {
+        if self.config.low_priority {
+            throttle::lower_priority();
+        }
+        let hashes = self.find_hashes();
+        self.cancel.check()?;
+        info!("{} files hashed", hashes.len());
+
+        // As in ssdeep, hashes only compare at the same block size or double
+        // it, so each is compared within its block size and the next.
+        let mut by_block_size = BTreeMap::new();
+        for (index, (_, hash)) in hashes.iter().enumerate() {
+            by_block_size
+                .entry(hash.block_size())
+                .or_insert_with(Vec::new)
+                .push(index);
+        }
+        let mut parent = (0..hashes.len()).collect::<Vec<_>>();
+        let mut scores = Vec::new();
+        for (block_size, members) in &by_block_size {
+            let doubled = by_block_size
+                .get(&(block_size * 2))
+                .map_or(&[][..], Vec::as_slice);
+            for (position, &a) in members.iter().enumerate() {
+                self.cancel.check()?;
+                let hash_a = &hashes[a].1;
+                for &b in members[position + 1..].iter().chain(doubled) {
+                    let hash_b = &hashes[b].1;
+                    if hash_a.max_score(hash_b) < self.threshold {
+                        continue;
+                    }
+                    let score = hash_a.compare(hash_b);
+                    if score >= self.threshold {
+                        let (root_a, root_b) = (find(&mut parent, a), find(&mut parent, b));
+                        parent[root_b] = root_a;
+                        scores.push((a, b, score));
+                    }
+                }
+            }
+        }
+
+        let mut clusters = BTreeMap::new();
+        for member in 0..hashes.len() {
+            let root = find(&mut parent, member);
+            clusters.entry(root).or_insert_with(Vec::new).push(member);
+        }
+        let clusters = clusters
+            .into_values()
+            .filter(|members| members.len() > 1)
+            .collect::<Vec<_>>();
+        let total = clusters.len();
+        info!("{} clusters of similar files", total);
+
+        for (id, members) in clusters.into_iter().enumerate() {
+            let index = |file: usize| members.iter().position(|member| *member == file);
+            let message = SimilarMessage {
+                id,
+                total,
+                filenames: members.iter().map(|m| hashes[*m].0.clone()).collect(),
+                scores: scores
+                    .iter()
+                    .filter_map(|(a, b, score)| Some((index(*a)?, index(*b)?, *score)))
+                    .collect(),
+            };
+            debug!(message = debug(&message), "send");
+            self.tx.send(message)?;
+        }
+        Ok(())
+    }
+
+    fn find_hashes(&self) -> Vec<(PathBuf, FuzzyHash)> {
+        DupeScanner::walk(
+            &self.config,
+            &self.cancel,
+            &Progress::default(),
+            &self.throttle,
+        )
+            .into_iter()
+            .filter(|(size, _)| *size > 0)
+            .take_while(|_| !self.cancel.is_cancelled())
+            .filter_map(|(size, path)| match FuzzyHash::from_path(&path, size, &self.throttle) {
+                Ok(hash) => Some((path, hash)),
+                Err(e) => {
+                    debug!(path = debug(&path), error = debug(e), "fuzzy hash");
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+fn find(parent: &mut [usize], mut node: usize) -> usize {
+    while parent[node] != node {
+        parent[node] = parent[parent[node]];
+        node = parent[node];
+    }
+    node
+}
@@ -0,0 +1,459 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use notify::event::{AccessKind, AccessMode, ModifyKind, RenameMode};
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use tracing::{debug, info, warn};
+use walkdir::WalkDir;
+
+use super::fdupesgroup::FdupesGroup;
+use super::DupeScanner;
+use crate::error::{FdupesError, Result};
+use crate::throttle;
+use crate::{archive, ProgressStage};
+
+/// How long a file must go unchanged before it is compared, when no
+/// close-after-write event says it is complete.
+const SETTLE: Duration = Duration::from_secs(1);
+
+/// Every known file, bucketed as in a scan, each bucket split into groups
+/// of the same content (single files included).
+#[derive(Default)]
+struct Index<'a> {
+    buckets: BTreeMap<(u64, String), Vec<Known<'a>>>,
+    keys: HashMap<PathBuf, Vec<(u64, String)>>,
+}
+
+/// A group of the index, with the id it was first reported under.
+struct Known<'a> {
+    group: FdupesGroup<'a>,
+    id: Option<usize>,
+}
+
+impl<'a> Index<'a> {
+    fn insert(&mut self, key: (u64, String), group: FdupesGroup<'a>, id: Option<usize>) {
+        for filename in &group.filenames {
+            self.keys
+                .entry(filename.clone())
+                .or_default()
+                .push(key.clone());
+        }
+        self.buckets
+            .entry(key)
+            .or_default()
+            .push(Known { group, id });
+    }
+
+    /// Drop the file at `path`, or every file under it should it have been
+    /// a directory or an archive.
+    fn forget(&mut self, path: &Path) {
+        let gone = self
+            .keys
+            .keys()
+            .filter(|file| {
+                file.starts_with(path)
+                    || archive::split(file).is_some_and(|(archive, _)| archive.starts_with(path))
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        for file in gone {
+            for key in self.keys.remove(&file).unwrap_or_default() {
+                if let Some(groups) = self.buckets.get_mut(&key) {
+                    groups
+                        .iter_mut()
+                        .for_each(|known| known.group.remove(&file));
+                    groups.retain(|known| !known.group.filenames.is_empty());
+                    if groups.is_empty() {
+                        self.buckets.remove(&key);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl DupeScanner {
+    /// Scan, then keep watching the roots, reporting each group again, under
+    /// the id it was first reported with, whenever a new or changed file
+    /// joins it; `total` counts the groups reported so far. Runs until
+    /// cancelled. In low-priority mode this lowers the priority of the
+    /// calling thread.
+    pub fn watch(&self) -> Result<()> {
+        if self.config.low_priority {
+            throttle::lower_priority();
+        }
+        let (tx, events) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(move |event| {
+            // The scanner only stops listening once it is done.
+            let _ = tx.send(event);
+        })?;
+        let mode = if self.config.non_recursive {
+            RecursiveMode::NonRecursive
+        } else {
+            RecursiveMode::Recursive
+        };
+        // Events name absolute paths; the index keeps them as walked.
+        let mut watched = Vec::new();
+        for root in &self.config.roots {
+            watcher.watch(root, mode)?;
+            let absolute = root.canonicalize().map_err(|e| FdupesError::io(root, e))?;
+            watched.push((absolute, root.clone()));
+        }
+
+        let mut reported = 0;
+        let mut index = self.build_index(&mut reported)?;
+        self.progress.stage(ProgressStage::Done);
+        info!("watching {:?}", self.config.roots);
+
+        let mut changed: HashMap<PathBuf, Instant> = HashMap::new();
+        loop {
+            self.cancel.check()?;
+            match events.recv_timeout(SETTLE / 4) {
+                Ok(Ok(event)) => Self::note(&event, &watched, &mut index, &mut changed),
+                Ok(Err(e)) => warn!(error = debug(e), "watch"),
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => return Err(FdupesError::Disconnected),
+            }
+            let now = Instant::now();
+            let settled = changed
+                .iter()
+                .filter(|(_, at)| **at <= now)
+                .map(|(path, _)| path.clone())
+                .collect::<Vec<_>>();
+            for path in settled {
+                changed.remove(&path);
+                index.forget(&path);
+                // A new directory is walked as deep as the roots are.
+                let max_depth = if self.config.non_recursive {
+                    0
+                } else {
+                    usize::MAX
+                };
+                for file in WalkDir::new(&path)
+                    .max_depth(max_depth)
+                    .into_iter()
+                    .filter_map(|entry| entry.ok())
+                {
+                    // Files already in a new directory had events of their own.
+                    if file.file_type().is_file() && !index.keys.contains_key(file.path()) {
+                        self.add_file(&mut index, file.path(), &mut reported)?;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Record `event` in `changed`, as when each path will be ready to compare.
+    fn note(
+        event: &Event,
+        watched: &[(PathBuf, PathBuf)],
+        index: &mut Index,
+        changed: &mut HashMap<PathBuf, Instant>,
+    ) {
+        debug!(event = debug(event), "watch");
+        let now = Instant::now();
+        for path in &event.paths {
+            let path = &watched
+                .iter()
+                .find_map(|(absolute, root)| Some(root.join(path.strip_prefix(absolute).ok()?)))
+                .unwrap_or_else(|| path.clone());
+            match event.kind {
+                EventKind::Remove(_) | EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
+                    changed.remove(path);
+                    index.forget(path);
+                }
+                EventKind::Modify(ModifyKind::Metadata(_)) => {}
+                EventKind::Access(AccessKind::Close(AccessMode::Write))
+                | EventKind::Modify(ModifyKind::Name(_)) => {
+                    changed.insert(path.clone(), now);
+                }
+                EventKind::Create(_) | EventKind::Modify(_) => {
+                    changed.insert(path.clone(), now + SETTLE);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Group every file under the roots, reporting the duplicates found.
+    fn build_index(&self, reported: &mut usize) -> Result<Index<'_>> {
+        let files = self.walk_roots()?;
+        let buckets = self.bucket_all_files(&files);
+        self.cancel.check()?;
+        self.progress.comparing(buckets.len());
+        let mut index = Index::default();
+        for ((size, comparator_name), filenames) in buckets {
+            let comparator = self.comparator(&comparator_name)?;
+            let groups = if filenames.len() > 1 {
+                self.match_bucket(size, &comparator_name, &filenames)?
+            } else {
+                Vec::new()
+            };
+            let grouped = groups
+                .iter()
+                .flat_map(|group| group.filenames.iter())
+                .cloned()
+                .collect::<HashSet<_>>();
+            for group in groups {
+                let id = (group.filenames.len() > 1).then_some(*reported);
+                if let Some(id) = id {
+                    *reported += 1;
+                    self.tx
+                        .send(group.to_dupe_group(*reported, id, &self.config.roots))?;
+                }
+                index.insert((size, comparator_name.clone()), group, id);
+            }
+            for filename in filenames.iter().filter(|f| !grouped.contains(*f)) {
+                index.insert(
+                    (size, comparator_name.clone()),
+                    self.new_group(filename, size, comparator),
+                    None,
+                );
+            }
+            self.progress.bucket_done();
+        }
+        Ok(index)
+    }
+
+    /// Add the file at `path` to the index, along with its members should it
+    /// be an archive to look into, reporting any group they join.
+    fn add_file<'a>(
+        &'a self,
+        index: &mut Index<'a>,
+        path: &Path,
+        reported: &mut usize,
+    ) -> Result<()> {
+        let raw_size = match path.metadata() {
+            Ok(metadata) => metadata.len(),
+            Err(e) => {
+                debug!(path = debug(path), error = debug(e), "metadata");
+                return Ok(());
+            }
+        };
+        if self.config.archives && archive::is_archive(path) {
+            match archive::members(path, &self.throttle) {
+                Ok(members) => {
+                    for (size, member) in members {
+                        self.add(index, &member, size, reported)?;
+                    }
+                }
+                Err(e) => debug!(archive = debug(path), error = debug(e), "members"),
+            }
+        }
+        self.add(index, path, raw_size, reported)
+    }
+
+    /// Add the file at `path`, of `raw_size` bytes, to the index, reporting
+    /// any group it joins.
+    fn add<'a>(
+        &'a self,
+        index: &mut Index<'a>,
+        path: &Path,
+        raw_size: u64,
+        reported: &mut usize,
+    ) -> Result<()> {
+        for key in self.bucket_keys(raw_size, path) {
+            let comparator = self.comparator(&key.1)?;
+            let mut file = self.new_group(path, key.0, comparator);
+            let groups = index.buckets.entry(key.clone()).or_default();
+            let mut joined = None;
+            for (position, known) in groups.iter_mut().enumerate() {
+                if self.same_content(&mut file, &mut known.group) {
+                    joined = Some(position);
+                    break;
+                }
+            }
+            match joined {
+                Some(position) => {
+                    let known = &mut groups[position];
+                    known.group.add(path);
+                    let id = *known.id.get_or_insert_with(|| {
+                        *reported += 1;
+                        *reported - 1
+                    });
+                    self.tx
+                        .send(known.group.to_dupe_group(*reported, id, &self.config.roots))?;
+                    index.keys.entry(path.to_owned()).or_default().push(key);
+                }
+                None => index.insert(key, file, None),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        archive, CancellationToken, Config, DupeScanner, ExactGroupComparator, FdupesError,
+    };
+    use std::fs;
+    use std::io::Write;
+    use std::path::Path;
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+    use std::time::Duration;
+
+    lazy_static::lazy_static! {
+    static ref SCRATCH: &'static Path = Path::new("test_data/watch_scratch");
+    static ref MOVED_SCRATCH: &'static Path = Path::new("test_data/watch_moved_scratch");
+    static ref SHALLOW_SCRATCH: &'static Path = Path::new("test_data/watch_shallow_scratch");
+    static ref ARCHIVE_SCRATCH: &'static Path = Path::new("test_data/watch_archive_scratch");
+    static ref TEST_DATA1: &'static Path = Path::new("test_data/file1.txt");
+    }
+
+    #[test]
+    fn reports_new_copies() {
+        fs::create_dir_all(*SCRATCH).unwrap();
+        fs::copy(*TEST_DATA1, SCRATCH.join("a.txt")).unwrap();
+        let config = Config {
+            roots: vec![SCRATCH.to_path_buf()],
+            ..Config::default()
+        };
+        let cancel = CancellationToken::new();
+        let (tx, rx) = mpsc::channel();
+        let scanner = DupeScanner::new(
+            tx,
+            Arc::new(config),
+            vec![Box::new(ExactGroupComparator::new())],
+        )
+        .unwrap()
+        .with_cancellation(cancel.clone());
+        let handle = thread::spawn(move || scanner.watch());
+
+        // Nothing to report until a copy turns up.
+        assert!(rx.recv_timeout(Duration::from_millis(500)).is_err());
+        fs::copy(*TEST_DATA1, SCRATCH.join("b.txt")).unwrap();
+        let group = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        cancel.cancel();
+        let result = handle.join().unwrap();
+        fs::remove_dir_all(*SCRATCH).unwrap();
+
+        let mut filenames = group.filenames().cloned().collect::<Vec<_>>();
+        filenames.sort();
+        assert_eq!(
+            filenames,
+            vec![SCRATCH.join("a.txt"), SCRATCH.join("b.txt")]
+        );
+        assert!(matches!(result, Err(FdupesError::Cancelled)));
+    }
+
+    #[test]
+    fn forgets_moved_directories() {
+        let (root, moved) = (MOVED_SCRATCH.join("root"), MOVED_SCRATCH.join("away"));
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::copy(*TEST_DATA1, root.join("a.txt")).unwrap();
+        fs::copy(*TEST_DATA1, root.join("sub/c.txt")).unwrap();
+        let config = Config {
+            roots: vec![root.clone()],
+            ..Config::default()
+        };
+        let cancel = CancellationToken::new();
+        let (tx, rx) = mpsc::channel();
+        let scanner = DupeScanner::new(
+            tx,
+            Arc::new(config),
+            vec![Box::new(ExactGroupComparator::new())],
+        )
+        .unwrap()
+        .with_cancellation(cancel.clone());
+        let handle = thread::spawn(move || scanner.watch());
+
+        // The initial scan reports the copy in `sub`, which then leaves the root.
+        let first = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        fs::rename(root.join("sub"), &moved).unwrap();
+        fs::copy(*TEST_DATA1, root.join("b.txt")).unwrap();
+        let group = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        cancel.cancel();
+        let _ = handle.join().unwrap();
+        fs::remove_dir_all(*MOVED_SCRATCH).unwrap();
+
+        let mut filenames = group.filenames().cloned().collect::<Vec<_>>();
+        filenames.sort();
+        assert_eq!(filenames, vec![root.join("a.txt"), root.join("b.txt")]);
+        // `a.txt` keeps its group, and the group its id.
+        assert_eq!(group.id, first.id);
+    }
+
+    #[test]
+    fn walks_new_directories_as_deep_as_roots() {
+        let (root, outside) = (SHALLOW_SCRATCH.join("root"), SHALLOW_SCRATCH.join("sub"));
+        fs::create_dir_all(&root).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        fs::copy(*TEST_DATA1, root.join("a.txt")).unwrap();
+        fs::copy(*TEST_DATA1, outside.join("c.txt")).unwrap();
+        let config = Config {
+            roots: vec![root.clone()],
+            non_recursive: true,
+            ..Config::default()
+        };
+        let cancel = CancellationToken::new();
+        let (tx, rx) = mpsc::channel();
+        let scanner = DupeScanner::new(
+            tx,
+            Arc::new(config),
+            vec![Box::new(ExactGroupComparator::new())],
+        )
+        .unwrap()
+        .with_cancellation(cancel.clone());
+        let handle = thread::spawn(move || scanner.watch());
+
+        // A directory moved into a non-recursive root is not looked into.
+        thread::sleep(Duration::from_millis(500));
+        fs::rename(&outside, root.join("sub")).unwrap();
+        thread::sleep(Duration::from_millis(500));
+        fs::copy(*TEST_DATA1, root.join("b.txt")).unwrap();
+        let group = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        cancel.cancel();
+        let _ = handle.join().unwrap();
+        fs::remove_dir_all(*SHALLOW_SCRATCH).unwrap();
+
+        let mut filenames = group.filenames().cloned().collect::<Vec<_>>();
+        filenames.sort();
+        assert_eq!(filenames, vec![root.join("a.txt"), root.join("b.txt")]);
+    }
+
+    #[test]
+    fn looks_into_new_archives() {
+        fs::create_dir_all(*ARCHIVE_SCRATCH).unwrap();
+        fs::copy(*TEST_DATA1, ARCHIVE_SCRATCH.join("a.txt")).unwrap();
+        let config = Config {
+            roots: vec![ARCHIVE_SCRATCH.to_path_buf()],
+            archives: true,
+            ..Config::default()
+        };
+        let cancel = CancellationToken::new();
+        let (tx, rx) = mpsc::channel();
+        let scanner = DupeScanner::new(
+            tx,
+            Arc::new(config),
+            vec![Box::new(ExactGroupComparator::new())],
+        )
+        .unwrap()
+        .with_cancellation(cancel.clone());
+        let handle = thread::spawn(move || scanner.watch());
+
+        thread::sleep(Duration::from_millis(500));
+        let archive = ARCHIVE_SCRATCH.join("b.zip");
+        let mut zip = zip::ZipWriter::new(fs::File::create(&archive).unwrap());
+        zip.start_file("b.txt", zip::write::FileOptions::default())
+            .unwrap();
+        zip.write_all(&fs::read(*TEST_DATA1).unwrap()).unwrap();
+        zip.finish().unwrap();
+        let group = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        cancel.cancel();
+        let _ = handle.join().unwrap();
+        fs::remove_dir_all(*ARCHIVE_SCRATCH).unwrap();
+
+        let filenames = group.filenames().cloned().collect::<Vec<_>>();
+        assert_eq!(
+            filenames,
+            vec![
+                ARCHIVE_SCRATCH.join("a.txt"),
+                archive::member_path(&archive, "b.txt")
+            ]
+        );
+    }
+}
@@ -0,0 +1,11 @@
+use std::path::PathBuf;
+
+/// A cluster of near-duplicate files. Each score links two files of the
+/// cluster, by index, with their similarity from 0 to 100.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SimilarMessage {
+    pub id: usize,
+    pub total: usize,
+    pub filenames: Vec<PathBuf>,
+    pub scores: Vec<(usize, usize, u32)>,
+}
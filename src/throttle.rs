@@ -0,0 +1,200 @@
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::reader::BLOCK_SIZE;
+
+/// Limits the rate of reads shared between every reader it wraps. Does
+/// nothing unless built with a rate.
+#[derive(Debug, Clone, Default)]
+pub struct Throttle {
+    inner: Option<Arc<Inner>>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    bytes_per_sec: f64,
+    /// When the bytes paid for so far will fit within the rate.
+    next: Mutex<Instant>,
+}
+
+impl Throttle {
+    pub fn new(bytes_per_sec: Option<u64>) -> Self {
+        Self {
+            inner: bytes_per_sec.filter(|rate| *rate > 0).map(|rate| {
+                Arc::new(Inner {
+                    bytes_per_sec: rate as f64,
+                    next: Mutex::new(Instant::now()),
+                })
+            }),
+        }
+    }
+
+    /// Pay for `bytes` about to be read, first sleeping until the bytes paid
+    /// for before them fit within the rate.
+    pub fn consume(&self, bytes: u64) {
+        let Some(inner) = &self.inner else {
+            return;
+        };
+        let wait = {
+            let mut next = inner.lock();
+            let now = Instant::now();
+            let start = (*next).max(now);
+            *next = start + inner.duration(bytes);
+            start - now
+        };
+        thread::sleep(wait);
+    }
+
+    /// Give back `bytes` paid for by [`Self::consume`] but never read.
+    pub(crate) fn refund(&self, bytes: u64) {
+        if let Some(inner) = &self.inner {
+            let mut next = inner.lock();
+            *next = next.checked_sub(inner.duration(bytes)).unwrap_or(*next);
+        }
+    }
+
+    /// Throttle the bytes read from `reader`.
+    pub fn wrap(&self, reader: Box<dyn BufRead>) -> Box<dyn BufRead> {
+        if self.inner.is_some() {
+            Box::new(BufReader::with_capacity(BLOCK_SIZE, self.reader(reader)))
+        } else {
+            reader
+        }
+    }
+
+    /// Throttle the bytes read from `reader`, paying for each read before
+    /// making it. Reads are at most a block long, so none runs far ahead of
+    /// the rate.
+    pub(crate) fn reader<R: Read>(&self, reader: R) -> Throttled<R> {
+        Throttled {
+            reader,
+            throttle: self.clone(),
+        }
+    }
+}
+
+impl Inner {
+    fn lock(&self) -> MutexGuard<'_, Instant> {
+        match self.next.lock() {
+            Ok(next) => next,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    fn duration(&self, bytes: u64) -> Duration {
+        Duration::from_secs_f64(bytes as f64 / self.bytes_per_sec)
+    }
+}
+
+pub(crate) struct Throttled<R> {
+    reader: R,
+    throttle: Throttle,
+}
+
+impl<R: Read> Read for Throttled<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(BLOCK_SIZE);
+        self.throttle.consume(len as u64);
+        let read = self.reader.read(&mut buf[..len]);
+        let unread = len - read.as_ref().map_or(0, |read| *read);
+        self.throttle.refund(unread as u64);
+        read
+    }
+}
+
+impl<R: Seek> Seek for Throttled<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.reader.seek(pos)
+    }
+}
+
+/// Parse a read rate such as `50MiB/s`, `1.5G` or `65536` into bytes per
+/// second. `K`, `M` and `G` are decimal; `Ki`, `Mi` and `Gi` binary.
+pub fn parse_rate(rate: &str) -> Result<u64, String> {
+    let trimmed = rate.trim();
+    let trimmed = trimmed.strip_suffix("/s").unwrap_or(trimmed);
+    let trimmed = trimmed.strip_suffix('B').unwrap_or(trimmed);
+    let split = trimmed
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(trimmed.len());
+    let (number, unit) = trimmed.split_at(split);
+    let multiplier: u64 = match unit.trim() {
+        "" => 1,
+        "K" | "k" => 1_000,
+        "Ki" => 1 << 10,
+        "M" => 1_000_000,
+        "Mi" => 1 << 20,
+        "G" => 1_000_000_000,
+        "Gi" => 1 << 30,
+        unit => return Err(format!("unknown unit {unit:?} in {rate:?}")),
+    };
+    let number = number
+        .parse::<f64>()
+        .map_err(|e| format!("invalid rate {rate:?}: {e}"))?;
+    let bytes = (number * multiplier as f64) as u64;
+    if bytes == 0 {
+        return Err(format!("rate {rate:?} must be at least 1 byte per second"));
+    }
+    Ok(bytes)
+}
+
+/// Drop the calling thread to idle I/O priority and the lowest CPU priority.
+/// Threads it spawns afterwards inherit both. Linux only; elsewhere, or when
+/// refused, the scan carries on at normal priority.
+pub(crate) fn lower_priority() {
+    #[cfg(target_os = "linux")]
+    {
+        use tracing::warn;
+
+        const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+        const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+        const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+
+        // SAFETY: plain syscalls on the calling thread, taking no pointers.
+        unsafe {
+            if libc::syscall(
+                libc::SYS_ioprio_set,
+                IOPRIO_WHO_PROCESS,
+                0,
+                IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+            ) != 0
+            {
+                warn!(error = debug(io::Error::last_os_error()), "ioprio_set");
+            }
+            if libc::setpriority(libc::PRIO_PROCESS, 0, 19) != 0 {
+                warn!(error = debug(io::Error::last_os_error()), "setpriority");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn rates() {
+        assert_eq!(parse_rate("50MiB/s"), Ok(50 << 20));
+        assert_eq!(parse_rate("1.5G"), Ok(1_500_000_000));
+        assert_eq!(parse_rate("65536"), Ok(65536));
+        assert_eq!(parse_rate("10 KiB"), Ok(10 << 10));
+        assert!(parse_rate("10 parsecs").is_err());
+        assert!(parse_rate("0").is_err());
+    }
+
+    #[test]
+    fn throttles() {
+        let throttle = Throttle::new(Some(100_000));
+        let start = Instant::now();
+        let mut reader = throttle.wrap(Box::new(Cursor::new(vec![0_u8; 20_000])));
+        io::copy(&mut reader, &mut io::sink()).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(190));
+
+        let unlimited = Throttle::default();
+        let mut reader = unlimited.wrap(Box::new(Cursor::new(vec![0_u8; 20_000])));
+        assert_eq!(io::copy(&mut reader, &mut io::sink()).unwrap(), 20_000);
+    }
+}